@@ -0,0 +1,79 @@
+//! Checks that the cycle counter works on this machine and prints what was detected.
+//!
+//! ```text
+//! cargo run --release --example selftest
+//! ```
+//!
+//! Exits with a non-zero status if the counter does not advance or its resolution is implausible.
+
+use criterion_cycles_per_byte::{detect, Backend};
+use std::{process, time::Duration};
+
+/// Back-to-back reads slower than this suggest `rdtsc` is being trapped and emulated.
+const MAX_PLAUSIBLE_RESOLUTION: u64 = 10_000;
+
+fn main() {
+    detect::assert_counter_works();
+    println!("counter advances:   yes");
+
+    let mut ok = true;
+
+    let resolution = detect::timer_resolution();
+    println!("timer resolution:   {} cycles", resolution);
+    if resolution == 0 || resolution > MAX_PLAUSIBLE_RESOLUTION {
+        eprintln!("error: implausible timer resolution");
+        ok = false;
+    }
+
+    println!("invariant TSC:      {}", detect::invariant_tsc());
+    println!("target-cpu matches: {}", !detect::target_cpu_mismatch());
+
+    let measured = detect::measure_frequency(Duration::from_millis(100));
+    println!("measured frequency: {:.3} GHz", measured as f64 / 1e9);
+    if measured == 0 {
+        eprintln!("error: the counter did not advance");
+        ok = false;
+    }
+
+    match detect::cpuid_tsc_frequency() {
+        Some(hz) => println!(
+            "CPUID frequency:    {:.3} GHz ({:+.2}% measured)",
+            hz as f64 / 1e9,
+            (measured as f64 / hz as f64 - 1.0) * 100.0
+        ),
+        None => println!("CPUID frequency:    not reported"),
+    }
+    for (label, hz) in [
+        ("hypervisor TSC:", detect::hypervisor_tsc_frequency()),
+        ("sysfs base:", detect::sysfs_base_frequency()),
+    ] {
+        match hz {
+            Some(hz) => println!("{:<19} {:.3} GHz", label, hz as f64 / 1e9),
+            None => println!("{:<19} not reported", label),
+        }
+    }
+
+    let backends = [
+        Backend::Rdtsc,
+        Backend::Serialized,
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        Backend::FixedCounter,
+        #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+        Backend::Rdpmc,
+    ];
+    for backend in &backends {
+        println!(
+            "backend {:?}: {}",
+            backend,
+            if backend.is_available() {
+                "available"
+            } else {
+                "unavailable"
+            }
+        );
+    }
+
+    if !ok {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,86 @@
+//! Pinning the calling thread to a single CPU.
+
+#[cfg(not(windows))]
+use std::io;
+
+/// Returns the CPU the calling thread is currently running on.
+#[cfg(target_os = "linux")]
+pub(crate) fn current_cpu() -> io::Result<usize> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cpu as usize)
+}
+
+/// Restricts the calling thread to `cpu`.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) use self::windows::{current_cpu, pin_current_thread};
+
+#[cfg(windows)]
+pub(crate) mod windows {
+    use std::{ffi::c_void, io};
+
+    type Handle = *mut c_void;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> Handle;
+        fn GetCurrentProcessorNumber() -> u32;
+        fn SetThreadAffinityMask(thread: Handle, mask: usize) -> usize;
+        fn QueryThreadCycleTime(thread: Handle, cycles: *mut u64) -> i32;
+    }
+
+    pub(crate) fn current_cpu() -> io::Result<usize> {
+        Ok(unsafe { GetCurrentProcessorNumber() } as usize)
+    }
+
+    /// Restricts the calling thread to `cpu` within its processor group, which holds at most 64
+    /// logical processors.
+    pub(crate) fn pin_current_thread(cpu: usize) -> io::Result<()> {
+        if cpu >= usize::BITS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SetThreadAffinityMask only addresses the first 64 processors of a group",
+            ));
+        }
+        if unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << cpu) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The cycles the kernel has accounted to the calling thread.
+    pub(crate) fn thread_cycles() -> Option<u64> {
+        let mut cycles = 0;
+        if unsafe { QueryThreadCycleTime(GetCurrentThread(), &mut cycles) } == 0 {
+            return None;
+        }
+        Some(cycles)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn current_cpu() -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "thread affinity is not supported on this platform",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    current_cpu().map(drop)
+}
@@ -0,0 +1,219 @@
+//! cpb results saved as JSON, to compare later runs against.
+//!
+//! A baseline file holds one object with the format version and the cpb of each benchmark id:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "commit": "5f3c2a1",
+//!   "cpb": {
+//!     "aes/16": 4.1,
+//!     "aes/4096": 0.62
+//!   }
+//! }
+//! ```
+//!
+//! Ids are sorted so that a committed baseline only changes where the results do. `commit` is
+//! the commit the results were measured at, and is left out if unknown.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Baseline {
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+    cpb: BTreeMap<String, f64>,
+}
+
+/// A benchmark whose cpb got worse than its baseline allows, see
+/// [`CyclesPerByte::check_baseline`](crate::CyclesPerByte::check_baseline).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Regression {
+    /// The benchmark id.
+    pub id: String,
+    /// The cpb in the baseline.
+    pub baseline: f64,
+    /// The cpb of this run.
+    pub current: f64,
+}
+
+impl Regression {
+    /// Returns how many times slower this run was than the baseline, e.g. 1.1 for 10% slower.
+    pub fn ratio(&self) -> f64 {
+        self.current / self.baseline
+    }
+}
+
+/// Writes `cpb`, measured at `commit`, to `path`, replacing the file.
+pub(crate) fn write(
+    path: &Path,
+    commit: Option<String>,
+    cpb: BTreeMap<String, f64>,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(
+        &mut file,
+        &Baseline {
+            version: VERSION,
+            commit,
+            cpb,
+        },
+    )?;
+    file.write_all(b"\n")?;
+    file.flush()
+}
+
+/// Reads the cpb of each id from `path`, failing with [`io::ErrorKind::InvalidData`] if it is not
+/// a baseline of this version.
+pub(crate) fn read(path: &Path) -> io::Result<BTreeMap<String, f64>> {
+    Ok(read_baseline(path)?.cpb)
+}
+
+fn read_baseline(path: &Path) -> io::Result<Baseline> {
+    let baseline: Baseline = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if baseline.version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported baseline version {}", baseline.version),
+        ));
+    }
+    Ok(baseline)
+}
+
+/// Reads the cpb of `id` from each baseline in `paths`, saved with
+/// [`CyclesPerByte::save_baseline`](crate::CyclesPerByte::save_baseline), along with the commit
+/// it was measured at, for [`bisect_regression`].
+///
+/// The series is in the order of `paths`, which should be that of the commits, oldest first.
+/// Baselines without `id` are skipped, and one without a commit is named by its path instead.
+/// Fails on the first file that cannot be read or is not a baseline.
+///
+/// ```rust
+/// # #[cfg(feature = "criterion")]
+/// # {
+/// use criterion::{measurement::Measurement, Throughput};
+/// use criterion_cycles_per_byte::{metadata, read_baseline_series, CyclesPerByte};
+///
+/// let mut paths = Vec::new();
+/// for (commit, cycles) in [("a1", 64.0), ("b2", 80.0)] {
+///     let measurement = CyclesPerByte::new().track_cpb().with_provenance(commit);
+///     metadata::set_id("xor/16");
+///     measurement
+///         .formatter()
+///         .format_throughput(&Throughput::Bytes(16), cycles);
+///     metadata::clear();
+///     let path = std::env::temp_dir().join(format!("cpb-{}-{}.json", commit, std::process::id()));
+///     measurement.save_baseline(&path)?;
+///     paths.push(path);
+/// }
+///
+/// let series = read_baseline_series(&paths, "xor/16")?;
+/// assert_eq!(series, [("a1".to_owned(), 4.0), ("b2".to_owned(), 5.0)]);
+/// assert!(read_baseline_series(&paths, "xor/4096")?.is_empty());
+/// for path in &paths {
+///     std::fs::remove_file(path)?;
+/// }
+/// # }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn read_baseline_series<P: AsRef<Path>>(
+    paths: &[P],
+    id: &str,
+) -> io::Result<Vec<(String, f64)>> {
+    let mut series = Vec::with_capacity(paths.len());
+    for path in paths {
+        let path = path.as_ref();
+        let baseline = read_baseline(path)?;
+        if let Some(&cpb) = baseline.cpb.get(id) {
+            let commit = baseline
+                .commit
+                .unwrap_or_else(|| path.display().to_string());
+            series.push((commit, cpb));
+        }
+    }
+    Ok(series)
+}
+
+/// The commit at which a benchmark got slower, see [`bisect_regression`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegressedCommit {
+    /// The commit.
+    pub commit: String,
+    /// The cpb at the commit before it.
+    pub before: f64,
+    /// The cpb at the commit.
+    pub after: f64,
+}
+
+impl RegressedCommit {
+    /// Returns how many times slower the commit was than its predecessor, e.g. 1.1 for 10% slower.
+    pub fn ratio(&self) -> f64 {
+        self.after / self.before
+    }
+}
+
+/// Returns the first commit of `series` whose cpb exceeds that of the commit before it by more
+/// than the fraction `threshold`, e.g. 0.05 for 5%, or `None` if there is no such step.
+///
+/// `series` holds the cpb of one benchmark per commit, oldest first, as [`read_baseline_series`]
+/// reads it. Each commit is compared with its predecessor only, so pick a threshold above the
+/// run-to-run variation of the benchmark; a regression spread over several smaller steps is not
+/// found.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::bisect_regression;
+///
+/// let series = [
+///     ("a1".to_owned(), 4.00),
+///     ("b2".to_owned(), 4.02),
+///     ("c3".to_owned(), 3.98),
+///     ("d4".to_owned(), 4.60),
+///     ("e5".to_owned(), 4.62),
+///     ("f6".to_owned(), 5.50),
+/// ];
+/// let regressed = bisect_regression(&series, 0.05).unwrap();
+/// assert_eq!((regressed.commit.as_str(), regressed.before, regressed.after), ("d4", 3.98, 4.60));
+/// assert_eq!(bisect_regression(&series[..3], 0.05), None);
+/// assert_eq!(bisect_regression(&series, 0.2), None);
+/// assert_eq!(bisect_regression(&series, 0.16).unwrap().commit, "f6");
+/// assert_eq!(bisect_regression(&[], 0.05), None);
+/// ```
+pub fn bisect_regression(series: &[(String, f64)], threshold: f64) -> Option<RegressedCommit> {
+    series
+        .windows(2)
+        .find(|pair| pair[1].1 > pair[0].1 * (1.0 + threshold))
+        .map(|pair| RegressedCommit {
+            commit: pair[1].0.clone(),
+            before: pair[0].1,
+            after: pair[1].1,
+        })
+}
+
+/// Returns the ids in both `baseline` and `current` whose cpb exceeds the baseline by more than
+/// the fraction `tolerance`.
+pub(crate) fn compare(
+    baseline: &BTreeMap<String, f64>,
+    current: &BTreeMap<String, f64>,
+    tolerance: f64,
+) -> Vec<Regression> {
+    current
+        .iter()
+        .filter_map(|(id, &current)| {
+            let baseline = *baseline.get(id)?;
+            (current > baseline * (1.0 + tolerance)).then(|| Regression {
+                id: id.clone(),
+                baseline,
+                current,
+            })
+        })
+        .collect()
+}
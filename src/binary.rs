@@ -0,0 +1,243 @@
+//! A compact file format for recorded samples.
+//!
+//! All integers are little-endian, whatever the byte order of the machine writing or reading:
+//!
+//! | offset   | size | contents                                                         |
+//! |---------:|-----:|------------------------------------------------------------------|
+//! |        0 |    4 | magic `CPBS`                                                     |
+//! |        4 |    2 | format version, currently 3                                      |
+//! |        6 |    2 | architecture that measured: 1 for x86_64, 2 for x86, 0 otherwise |
+//! |        8 |    8 | counter frequency in Hz, 0 if unknown                            |
+//! |       16 |    8 | number of samples `n`                                            |
+//! |       24 |    2 | length `c` of the commit, 0 if unknown                           |
+//! |       26 |  `c` | the commit the measured code was built from, as UTF-8            |
+//! | 26 + `c` |    2 | length `m` of the machine identifier, 0 if unknown               |
+//! | 28 + `c` |  `m` | the machine that measured, as UTF-8                              |
+//! | 28+`c+m` | 8`n` | the samples, as `u64` cycle counts in the order they were taken  |
+//!
+//! Version 2 files lack the machine identifier: their samples start at offset 26 + `c`. Version 1
+//! files lack the commit as well: their samples start at offset 24. Both are still read.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: [u8; 4] = *b"CPBS";
+const VERSION: u16 = 3;
+
+#[cfg(target_arch = "x86_64")]
+const ARCH: u16 = 1;
+#[cfg(target_arch = "x86")]
+const ARCH: u16 = 2;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+const ARCH: u16 = 0;
+
+/// The header of a sample dump, see [`read_binary_header`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DumpHeader {
+    /// The format version of the file.
+    pub version: u16,
+    /// The architecture that measured: 1 for x86_64, 2 for x86, 0 otherwise.
+    pub arch: u16,
+    /// The counter frequency in Hz, 0 if unknown.
+    pub frequency: u64,
+    /// The number of samples.
+    pub count: u64,
+    /// The commit the measured code was built from, see
+    /// [`CyclesPerByte::with_provenance`](crate::CyclesPerByte::with_provenance).
+    pub commit: Option<String>,
+    /// The machine that measured, see
+    /// [`CyclesPerByte::with_machine_id`](crate::CyclesPerByte::with_machine_id).
+    pub machine: Option<String>,
+}
+
+/// Writes `samples` to `path` with a header giving the counter `frequency`, the `commit` and the
+/// `machine`.
+pub(crate) fn write(
+    path: &Path,
+    frequency: u64,
+    commit: Option<&str>,
+    machine: Option<&str>,
+    samples: &[u64],
+) -> io::Result<()> {
+    let commit = commit.unwrap_or("").as_bytes();
+    let machine = machine.unwrap_or("").as_bytes();
+    if commit.len() > usize::from(u16::MAX) || machine.len() > usize::from(u16::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the commit or machine identifier is longer than 65535 bytes",
+        ));
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&MAGIC)?;
+    file.write_all(&VERSION.to_le_bytes())?;
+    file.write_all(&ARCH.to_le_bytes())?;
+    file.write_all(&frequency.to_le_bytes())?;
+    file.write_all(&(samples.len() as u64).to_le_bytes())?;
+    for text in [commit, machine] {
+        file.write_all(&(text.len() as u16).to_le_bytes())?;
+        file.write_all(text)?;
+    }
+    for sample in samples {
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    file.flush()
+}
+
+/// Reads the samples written by [`CyclesPerByte::dump_binary`](crate::CyclesPerByte::dump_binary).
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if the file does not start with the magic, has
+/// an unknown version, or holds more or fewer samples than its header says. The header's
+/// architecture, frequency, commit and machine are only informational and are not checked; see
+/// [`read_binary_header`] for them.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::{read_binary, CyclesPerByte};
+///
+/// let measurement = CyclesPerByte::new()
+///     .with_provenance("")
+///     .with_machine_id("")
+///     .record_samples();
+/// for _ in 0..3 {
+///     measurement.measure(|| ());
+/// }
+///
+/// let path = std::env::temp_dir().join(format!("cpb-doctest-{}.bin", std::process::id()));
+/// measurement.dump_binary(&path)?;
+/// assert_eq!(std::fs::metadata(&path)?.len(), 28 + 3 * 8);
+/// assert_eq!(read_binary(&path)?, measurement.samples());
+///
+/// std::fs::write(&path, b"not samples")?;
+/// assert_eq!(read_binary(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+/// std::fs::remove_file(&path)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn read_binary<P: AsRef<Path>>(path: P) -> io::Result<Vec<u64>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let header = read_header(&mut file)?;
+
+    let mut samples = Vec::new();
+    let mut sample = [0u8; 8];
+    for _ in 0..header.count {
+        read_or_invalid(&mut file, &mut sample)?;
+        samples.push(u64::from_le_bytes(sample));
+    }
+    if file.read(&mut sample)? != 0 {
+        return Err(invalid("trailing data after the samples"));
+    }
+    Ok(samples)
+}
+
+/// Reads the header of a file written by
+/// [`CyclesPerByte::dump_binary`](crate::CyclesPerByte::dump_binary), without the samples.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] like [`read_binary`], except that the samples are
+/// not checked.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::{read_binary_header, CyclesPerByte, FrequencySource};
+///
+/// let measurement = CyclesPerByte::new()
+///     .frequency_source(FrequencySource::Fixed(2_400_000_000))
+///     .with_provenance("5f3c2a1")
+///     .with_machine_id("bench-03")
+///     .record_samples();
+/// measurement.measure(|| ());
+///
+/// let path = std::env::temp_dir().join(format!("cpb-header-{}.bin", std::process::id()));
+/// measurement.dump_binary(&path)?;
+/// let header = read_binary_header(&path)?;
+/// assert_eq!(header.version, 3);
+/// assert_eq!(header.frequency, 2_400_000_000);
+/// assert_eq!(header.count, 1);
+/// assert_eq!(header.commit.as_deref(), Some("5f3c2a1"));
+/// assert_eq!(header.machine.as_deref(), Some("bench-03"));
+/// std::fs::remove_file(&path)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn read_binary_header<P: AsRef<Path>>(path: P) -> io::Result<DumpHeader> {
+    read_header(&mut BufReader::new(File::open(path)?))
+}
+
+fn read_header(file: &mut impl Read) -> io::Result<DumpHeader> {
+    let mut header = [0u8; 24];
+    read_or_invalid(file, &mut header)?;
+    if header[..4] != MAGIC {
+        return Err(invalid("not a sample dump"));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version == 0 || version > VERSION {
+        return Err(invalid("unsupported sample dump version"));
+    }
+    let u64_at = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&header[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let frequency = u64_at(8);
+    let count = u64_at(16);
+
+    let commit = if version >= 2 { read_text(file)? } else { None };
+    let machine = if version >= 3 { read_text(file)? } else { None };
+    Ok(DumpHeader {
+        version,
+        arch: u16::from_le_bytes([header[6], header[7]]),
+        frequency,
+        count,
+        commit,
+        machine,
+    })
+}
+
+/// Reads a length-prefixed UTF-8 string of the header, `None` if it is empty.
+fn read_text(file: &mut impl Read) -> io::Result<Option<String>> {
+    let mut len = [0u8; 2];
+    read_or_invalid(file, &mut len)?;
+    let mut bytes = vec![0u8; usize::from(u16::from_le_bytes(len))];
+    read_or_invalid(file, &mut bytes)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|_| invalid("header text is not UTF-8"))
+}
+
+/// Fills `buf`, reporting a truncated file as invalid data.
+fn read_or_invalid(file: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    file.read_exact(buf).map_err(|error| {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            invalid("truncated sample dump")
+        } else {
+            error
+        }
+    })
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::scripted;
+
+    #[test]
+    fn dumped_samples_read_back() {
+        let measurement = scripted(&[0, 1, 10, 12, 20, 23])
+            .with_provenance("")
+            .with_machine_id("")
+            .record_samples();
+        for _ in 0..3 {
+            measurement.measure(|| ());
+        }
+        let path = std::env::temp_dir().join(format!("cpb-test-{}.bin", std::process::id()));
+        measurement.dump_binary(&path).unwrap();
+        assert_eq!(read_binary(&path).unwrap(), [1, 2, 3]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
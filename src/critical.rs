@@ -0,0 +1,39 @@
+//! Measuring with interrupts masked, for bare-metal targets.
+
+use crate::now;
+
+/// Measures a single call of `f` inside a critical section and returns the cycles it took.
+///
+/// On bare metal, interrupt handlers are the main source of noise, so both counter reads and `f`
+/// run in `critical_section::with`, with whatever [`critical-section`] implementation the
+/// firmware links in. Pending interrupts are delayed by the length of `f`. Needs neither `std`
+/// nor an allocator; on hosted targets nothing is masked.
+///
+/// [`critical-section`]: https://docs.rs/critical-section/1
+///
+/// ```rust
+/// use criterion_cycles_per_byte::measure_critical;
+///
+/// let cycles = measure_critical(|| (0..64u32).sum::<u32>());
+/// assert!(cycles > 0);
+/// ```
+#[inline]
+pub fn measure_critical<F, R>(f: F) -> u64
+where
+    F: FnOnce() -> R,
+{
+    critical_section::with(|_| {
+        let start = now();
+        core::hint::black_box(f());
+        let end = now();
+
+        // `DWT_CYCCNT` is 32 bits wide and wraps.
+        #[cfg(all(target_arch = "arm", target_os = "none"))]
+        {
+            u64::from((end as u32).wrapping_sub(start as u32))
+        }
+
+        #[cfg(not(all(target_arch = "arm", target_os = "none")))]
+        end.saturating_sub(start)
+    })
+}
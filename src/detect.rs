@@ -0,0 +1,473 @@
+//! Probing the host's counters before trusting the numbers they produce.
+//!
+//! ```rust
+//! use criterion_cycles_per_byte::detect;
+//!
+//! if !detect::invariant_tsc() {
+//!     eprintln!("the TSC rate follows the core frequency, cycle counts will drift");
+//! }
+//! assert!(detect::timer_resolution() > 0);
+//! ```
+
+use crate::{now, Backend, FrequencySource};
+use std::time::{Duration, Instant};
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, CpuidResult};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
+/// Documentation stub for other targets, which cannot be compiled otherwise.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+struct CpuidResult {
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    edx: u32,
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+fn cpuid(_leaf: u32) -> Option<CpuidResult> {
+    None
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[allow(unused_unsafe)]
+fn cpuid(leaf: u32) -> Option<CpuidResult> {
+    // The basic, hypervisor and extended ranges each report their highest leaf in their first.
+    let max = leaf & 0xffff_0000;
+    if leaf > unsafe { __cpuid(max) }.eax {
+        return None;
+    }
+    Some(unsafe { __cpuid(leaf) })
+}
+
+/// Returns whether the TSC ticks at a constant rate in all ACPI P-, C- and T-states
+/// (CPUID `0x8000_0007`, `EDX` bit 8).
+///
+/// Without an invariant TSC, `rdtsc` readings scale with frequency changes and stop in deep
+/// sleep states, so deltas from different parts of a run are not comparable.
+pub fn invariant_tsc() -> bool {
+    cpuid(0x8000_0007).is_some_and(|r| r.edx & (1 << 8) != 0)
+}
+
+/// Returns the nominal TSC frequency in Hz as reported by CPUID.
+///
+/// Leaf `0x15` gives the TSC/crystal ratio and, on most recent Intel parts, the crystal
+/// frequency. If the crystal frequency is not enumerated, the processor base frequency from leaf
+/// `0x16` is used instead, which matches the TSC rate on those parts. Returns `None` if neither
+/// leaf is available, which is the case on AMD processors and many hypervisors.
+pub fn cpuid_tsc_frequency() -> Option<u64> {
+    if let Some(r) = cpuid(0x15) {
+        if r.eax != 0 && r.ebx != 0 && r.ecx != 0 {
+            return Some(u64::from(r.ecx) * u64::from(r.ebx) / u64::from(r.eax));
+        }
+    }
+    cpuid(0x16)
+        .map(|r| u64::from(r.eax & 0xffff) * 1_000_000)
+        .filter(|&hz| hz != 0)
+}
+
+/// Returns the TSC frequency in Hz that the hypervisor reports, when running in a virtual machine.
+///
+/// VMware, and KVM and Xen when configured to, expose it in the timing leaf `0x4000_0010` of the
+/// hypervisor CPUID range. Guests commonly see no leaf `0x15` or a host value in it, so this is
+/// the more reliable source inside a VM. Returns `None` on bare metal (CPUID `1`, `ECX` bit 31
+/// clear) and if the hypervisor does not provide the leaf.
+pub fn hypervisor_tsc_frequency() -> Option<u64> {
+    cpuid(1).filter(|r| r.ecx & (1 << 31) != 0)?;
+    cpuid(0x4000_0010)
+        .map(|r| u64::from(r.eax) * 1000)
+        .filter(|&hz| hz != 0)
+}
+
+/// Returns the processor base frequency in Hz that Linux's cpufreq driver reports for CPU 0.
+///
+/// This is `/sys/devices/system/cpu/cpu0/cpufreq/base_frequency`, which the `intel_pstate` and
+/// `amd-pstate` drivers provide. The TSC of recent processors ticks at about the base frequency,
+/// but the nominal value can be off from the actual TSC rate by a fraction of a percent. Returns
+/// `None` if the file is absent, as it is without one of those drivers and in most virtual
+/// machines, and on other platforms.
+pub fn sysfs_base_frequency() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    return linux::base_frequency();
+
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Measures the rate of [`now()`] in Hz against the system's monotonic clock by busy-waiting for
+/// `duration`.
+///
+/// Longer durations give more precise results; 100ms is typically accurate to well under 0.1%.
+/// The thread should not migrate between cores while this runs unless the TSC is invariant.
+pub fn measure_frequency(duration: Duration) -> u64 {
+    let start = Instant::now();
+    let start_cycles = now();
+    while start.elapsed() < duration {}
+    let cycles = now().saturating_sub(start_cycles);
+    let elapsed = start.elapsed();
+    (cycles as f64 / elapsed.as_secs_f64()) as u64
+}
+
+/// Returns the smallest non-zero difference between back-to-back reads of [`now()`].
+///
+/// For `rdtsc` this is dominated by the cost of the read itself, typically a few dozen cycles on
+/// bare metal, and bounds the shortest region that can be measured meaningfully. A value of zero
+/// means the counter never advanced.
+pub fn timer_resolution() -> u64 {
+    let mut resolution = 0;
+    for _ in 0..10_000 {
+        let first = now();
+        let delta = now().saturating_sub(first);
+        if delta != 0 && (resolution == 0 || delta < resolution) {
+            resolution = delta;
+        }
+    }
+    resolution
+}
+
+/// Iterations of the workload of [`assert_counter_works`].
+const WORKLOAD_ITERATIONS: u64 = 1_000_000;
+
+/// Panics unless [`now()`] advances plausibly over a known workload.
+///
+/// The workload is a loop of volatile increments, which cannot be optimized away or folded and
+/// take at least one core cycle each. The check allows for a counter ticking at an eighth of the
+/// core clock, which covers the gap between a time-stamp counter and the highest turbo
+/// frequencies, so a counter that makes less progress is stuck, disabled or emulated at a coarse
+/// granularity, as in some virtual machines. Call it at the start of the benchmark harness's
+/// `main` to fail before a whole run produces all-zero or meaningless results:
+///
+/// ```rust
+/// criterion_cycles_per_byte::detect::assert_counter_works();
+/// ```
+pub fn assert_counter_works() {
+    let mut counter = 0u64;
+    let start = now();
+    for _ in 0..WORKLOAD_ITERATIONS {
+        unsafe {
+            let value = core::ptr::read_volatile(&counter);
+            core::ptr::write_volatile(&mut counter, value + 1);
+        }
+    }
+    let delta = now().saturating_sub(start);
+    assert!(
+        delta != 0,
+        "criterion-cycles-per-byte: the cycle counter did not advance"
+    );
+    assert!(
+        delta >= WORKLOAD_ITERATIONS / 8,
+        "criterion-cycles-per-byte: the cycle counter advanced by only {} over {} volatile \
+         increments",
+        delta,
+        WORKLOAD_ITERATIONS
+    );
+}
+
+/// Returns the cycles the operating system has accounted to the calling thread, on Windows.
+///
+/// This is `QueryThreadCycleTime`, which the kernel maintains from the TSC at every context switch
+/// and interrupt, so it advances at the same rate as [`now()`] but only while the thread runs.
+/// Comparing its delta over a benchmark with the cycles measured by `rdtsc` shows how much of the
+/// measured time the thread spent descheduled: the two agree on an undisturbed run. Returns
+/// `None` elsewhere or if the query fails.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::{detect, now};
+///
+/// if let Some(os_start) = detect::os_thread_cycles() {
+///     let start = now();
+///     // ... the work being measured ...
+///     let measured = now() - start;
+///     let accounted = detect::os_thread_cycles().unwrap() - os_start;
+///     println!("{} of {} cycles accounted to this thread", accounted, measured);
+/// }
+/// ```
+pub fn os_thread_cycles() -> Option<u64> {
+    #[cfg(windows)]
+    return crate::affinity::windows::thread_cycles();
+
+    #[cfg(not(windows))]
+    None
+}
+
+/// Pairs each instruction set extension named here with whether the binary was compiled to assume
+/// it and whether CPUID reports it, from the given leaf, register and bit.
+macro_rules! target_features {
+    ($($feature:tt => ($leaf:expr, $register:ident, $bit:expr)),* $(,)?) => {
+        [$((
+            $feature,
+            cfg!(target_feature = $feature),
+            cpuid($leaf).is_some_and(|r| r.$register & (1 << $bit) != 0),
+        )),*]
+    };
+}
+
+/// Returns whether the binary was compiled for instruction set extensions the host lacks, printing
+/// the missing ones to stderr.
+///
+/// A binary built with `-C target-cpu=native` dies with `SIGILL` on an older CPU. This compares
+/// the `cfg!(target_feature = ...)` of the SSE, AVX, AVX-512F, BMI, FMA, AES, SHA and similar
+/// extensions with the host's CPUID, so call it first thing in `main`. Returns `false` on non-x86
+/// targets.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::detect;
+///
+/// if detect::target_cpu_mismatch() {
+///     eprintln!("rebuild without -C target-cpu=native for this host");
+/// }
+/// ```
+pub fn target_cpu_mismatch() -> bool {
+    let features = target_features![
+        "sse2" => (1, edx, 26),
+        "sse3" => (1, ecx, 0),
+        "ssse3" => (1, ecx, 9),
+        "sse4.1" => (1, ecx, 19),
+        "sse4.2" => (1, ecx, 20),
+        "popcnt" => (1, ecx, 23),
+        "lzcnt" => (0x8000_0001, ecx, 5),
+        "bmi1" => (7, ebx, 3),
+        "bmi2" => (7, ebx, 8),
+        "movbe" => (1, ecx, 22),
+        "fma" => (1, ecx, 12),
+        "f16c" => (1, ecx, 29),
+        "avx" => (1, ecx, 28),
+        "avx2" => (7, ebx, 5),
+        "avx512f" => (7, ebx, 16),
+        "avx512bw" => (7, ebx, 30),
+        "avx512cd" => (7, ebx, 28),
+        "avx512dq" => (7, ebx, 17),
+        "avx512vl" => (7, ebx, 31),
+        "aes" => (1, ecx, 25),
+        "pclmulqdq" => (1, ecx, 1),
+        "sha" => (7, ebx, 29),
+        "adx" => (7, ebx, 19),
+        "rdrand" => (1, ecx, 30),
+    ];
+    let compiled: Vec<&str> = features
+        .iter()
+        .filter(|&&(_, compiled, _)| compiled)
+        .map(|&(feature, _, _)| feature)
+        .collect();
+    let detected: Vec<&str> = features
+        .iter()
+        .filter(|&&(_, _, detected)| detected)
+        .map(|&(feature, _, _)| feature)
+        .collect();
+    let missing = missing_features(&compiled, &detected);
+    if !missing.is_empty() {
+        eprintln!(
+            "criterion-cycles-per-byte: compiled for CPU features this host lacks: {}",
+            missing.join(", ")
+        );
+    }
+    !missing.is_empty()
+}
+
+/// Returns the features of `compiled` that are not in `detected`.
+fn missing_features<'a>(compiled: &[&'a str], detected: &[&str]) -> Vec<&'a str> {
+    compiled
+        .iter()
+        .copied()
+        .filter(|feature| !detected.contains(feature))
+        .collect()
+}
+
+/// Returns the number of fixed-function performance counters (CPUID `0xa`, `EDX` bits 0..=4), or 0
+/// if the processor has no architectural performance monitoring version 2 or later.
+#[cfg(all(feature = "rdpmc", target_os = "linux"))]
+pub(crate) fn fixed_counters() -> u32 {
+    match cpuid(0xa) {
+        Some(r) if r.eax & 0xff >= 2 => r.edx & 0x1f,
+        _ => 0,
+    }
+}
+
+/// Returns the number of general-purpose performance counters per logical processor (CPUID `0xa`,
+/// `EAX` bits 8..=15), or 0 without architectural performance monitoring.
+#[cfg(all(feature = "msr", target_os = "linux"))]
+pub(crate) fn general_counters() -> u32 {
+    match cpuid(0xa) {
+        Some(r) if r.eax & 0xff >= 1 => (r.eax >> 8) & 0xff,
+        _ => 0,
+    }
+}
+
+/// Returns whether the CPU supports `rdtscp` (CPUID `0x8000_0001`, `EDX` bit 27).
+pub(crate) fn has_rdtscp() -> bool {
+    cpuid(0x8000_0001).is_some_and(|r| r.edx & (1 << 27) != 0)
+}
+
+/// Fraction of a sampling interval an SMT sibling may spend busy before it counts as contending.
+#[cfg(target_os = "linux")]
+const SIBLING_BUSY_THRESHOLD: f64 = 0.25;
+
+/// Returns whether another hardware thread on the calling thread's physical core is busy.
+///
+/// Hyperthreads share the core's execution units, so a busy sibling inflates cycle counts. This
+/// samples the siblings' time in `/proc/stat` 50ms apart, for the CPU the thread last ran on, so
+/// pin the benchmark first. Returns `Some(false)` without siblings and `None` if the topology
+/// cannot be read, including on every platform but Linux.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::detect;
+///
+/// if detect::smt_sibling_busy() == Some(true) {
+///     eprintln!("another thread shares this core, expect noisy results");
+/// }
+/// ```
+pub fn smt_sibling_busy() -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        let cpu = linux::current_cpu()?;
+        let siblings: Vec<usize> = linux::thread_siblings(cpu)?
+            .into_iter()
+            .filter(|&sibling| sibling != cpu)
+            .collect();
+        if siblings.is_empty() {
+            return Some(false);
+        }
+
+        let before = linux::cpu_times()?;
+        std::thread::sleep(Duration::from_millis(50));
+        let after = linux::cpu_times()?;
+
+        let mut busy = false;
+        for sibling in siblings {
+            let (busy_before, total_before) = *before.get(&sibling)?;
+            let (busy_after, total_after) = *after.get(&sibling)?;
+            let total = total_after.saturating_sub(total_before);
+            if total != 0 {
+                let fraction = busy_after.saturating_sub(busy_before) as f64 / total as f64;
+                busy |= fraction > SIBLING_BUSY_THRESHOLD;
+            }
+        }
+        Some(busy)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{collections::HashMap, fs};
+
+    /// The base frequency of CPU 0 in Hz, which cpufreq gives in kHz.
+    pub(super) fn base_frequency() -> Option<u64> {
+        let path = "/sys/devices/system/cpu/cpu0/cpufreq/base_frequency";
+        let khz: u64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+        Some(khz * 1000).filter(|&hz| hz != 0)
+    }
+
+    /// The CPU the calling thread last ran on: field 39 of `/proc/self/stat`.
+    pub(super) fn current_cpu() -> Option<usize> {
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+        // The command name (field 2) may contain spaces, so count from the closing parenthesis.
+        let fields = &stat[stat.rfind(')')? + 1..];
+        fields.split_whitespace().nth(36)?.parse().ok()
+    }
+
+    /// The hardware threads sharing the physical core of `cpu`, including `cpu` itself.
+    pub(super) fn thread_siblings(cpu: usize) -> Option<Vec<usize>> {
+        let path = format!(
+            "/sys/devices/system/cpu/cpu{}/topology/thread_siblings_list",
+            cpu
+        );
+        parse_cpu_list(fs::read_to_string(path).ok()?.trim())
+    }
+
+    /// Parses the kernel's CPU list format, e.g. `0-3,8,10-11`.
+    fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+        let mut cpus = Vec::new();
+        for range in list.split(',') {
+            match range.split_once('-') {
+                Some((first, last)) => {
+                    cpus.extend(first.parse::<usize>().ok()?..=last.parse().ok()?)
+                }
+                None => cpus.push(range.parse().ok()?),
+            }
+        }
+        Some(cpus)
+    }
+
+    /// `(busy, total)` jiffies per CPU from `/proc/stat`, where `idle` and `iowait` count as not busy.
+    pub(super) fn cpu_times() -> Option<HashMap<usize, (u64, u64)>> {
+        let stat = fs::read_to_string("/proc/stat").ok()?;
+        let mut times = HashMap::new();
+        for line in stat.lines() {
+            let mut fields = line.split_whitespace();
+            let cpu = match fields.next()?.strip_prefix("cpu") {
+                Some(cpu) if !cpu.is_empty() => cpu.parse().ok()?,
+                _ => continue,
+            };
+            let values: Vec<u64> = fields.filter_map(|field| field.parse().ok()).collect();
+            let total: u64 = values.iter().sum();
+            let idle = values.get(3).copied().unwrap_or(0) + values.get(4).copied().unwrap_or(0);
+            times.insert(cpu, (total - idle, total));
+        }
+        Some(times)
+    }
+}
+
+/// How long [`FrequencySource::Calibrated`] busy-waits.
+const CALIBRATION_TIME: Duration = Duration::from_millis(100);
+
+impl FrequencySource {
+    /// Returns the TSC frequency in Hz from this source, if it can tell.
+    pub(crate) fn resolve(self) -> Option<u64> {
+        match self {
+            FrequencySource::Auto => cpuid_tsc_frequency()
+                .or_else(hypervisor_tsc_frequency)
+                .or_else(sysfs_base_frequency)
+                .or_else(|| FrequencySource::Calibrated.resolve()),
+            FrequencySource::Cpuid => cpuid_tsc_frequency(),
+            FrequencySource::Hypervisor => hypervisor_tsc_frequency(),
+            FrequencySource::Sysfs => sysfs_base_frequency(),
+            FrequencySource::Calibrated => {
+                Some(measure_frequency(CALIBRATION_TIME)).filter(|&hz| hz != 0)
+            }
+            FrequencySource::Fixed(hz) => Some(hz),
+        }
+    }
+}
+
+impl Backend {
+    /// Returns whether this backend can be used on this host.
+    ///
+    /// Probing `Backend::FixedCounter` opens the msr device and enables the counter, exactly as
+    /// selecting it would.
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::Rdtsc => cpuid(1).is_some_and(|r| r.edx & (1 << 4) != 0),
+            // `lfence` comes with SSE2.
+            Backend::Serialized => {
+                cpuid(1).is_some_and(|r| r.edx & (1 << 4) != 0 && r.edx & (1 << 26) != 0)
+                    && has_rdtscp()
+            }
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            Backend::FixedCounter => crate::msr::FixedCounter::open().is_ok(),
+            #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+            Backend::Rdpmc => crate::rdpmc::available(),
+            Backend::Custom => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_features_lists_compiled_features_the_host_lacks() {
+        let compiled = ["sse2", "avx2", "avx512f", "bmi2"];
+        assert_eq!(
+            missing_features(&compiled, &["sse2", "avx2"]),
+            ["avx512f", "bmi2"]
+        );
+        assert!(missing_features(&compiled, &compiled).is_empty());
+        assert!(missing_features(&[], &["sse2"]).is_empty());
+    }
+}
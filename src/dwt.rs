@@ -0,0 +1,49 @@
+//! The cycle counter of the Data Watchpoint and Trace unit (DWT) on Cortex-M.
+//!
+//! `DWT_CYCCNT` is a 32-bit counter of core clock cycles, present on ARMv7-M (Cortex-M3, M4 and
+//! M7) and ARMv8-M Mainline (Cortex-M33, M35P and M55) when the implementation includes the DWT.
+//! ARMv6-M (Cortex-M0 and M0+) and ARMv8-M Baseline (Cortex-M23) have no cycle counter. The
+//! registers are at fixed addresses in the Private Peripheral Bus and can only be accessed in
+//! privileged mode.
+
+use core::ptr;
+
+/// Debug Exception and Monitor Control Register.
+const DEMCR: *mut u32 = 0xe000_edfc as *mut u32;
+/// Enables the DWT and ITM units (bit 24 of `DEMCR`).
+const DEMCR_TRCENA: u32 = 1 << 24;
+/// DWT Control Register.
+const DWT_CTRL: *mut u32 = 0xe000_1000 as *mut u32;
+/// Enables the cycle counter (bit 0 of `DWT_CTRL`).
+const DWT_CTRL_CYCCNTENA: u32 = 1;
+/// DWT Cycle Count Register.
+const DWT_CYCCNT: *mut u32 = 0xe000_1004 as *mut u32;
+/// DWT Lock Access Register, which the Cortex-M7 requires to be unlocked before `DWT_CTRL` can be
+/// written. Other cores treat it as reserved, reading as zero and ignoring writes.
+const DWT_LAR: *mut u32 = 0xe000_1fb0 as *mut u32;
+/// The value that unlocks the registers of a unit through its Lock Access Register.
+const DWT_LAR_KEY: u32 = 0xc5ac_ce55;
+
+/// Enables the DWT cycle counter, which [`now()`](crate::now) reads on Cortex-M.
+///
+/// The counter does not run out of reset: this sets `DEMCR.TRCENA` to power the trace unit,
+/// unlocks the DWT registers where the core requires it and sets `DWT_CTRL.CYCCNTENA`. Call it
+/// once at startup, before the first measurement and in privileged mode, which is what firmware
+/// runs in unless it has switched to an unprivileged thread mode. A debugger may have enabled the
+/// counter already, in which case this changes nothing; it does not reset the count.
+///
+/// On cores without a cycle counter `CYCCNTENA` ignores writes and [`now()`](crate::now) keeps
+/// returning zero.
+pub fn enable_dwt_cyccnt() {
+    unsafe {
+        ptr::write_volatile(DEMCR, ptr::read_volatile(DEMCR) | DEMCR_TRCENA);
+        ptr::write_volatile(DWT_LAR, DWT_LAR_KEY);
+        ptr::write_volatile(DWT_CTRL, ptr::read_volatile(DWT_CTRL) | DWT_CTRL_CYCCNTENA);
+    }
+}
+
+/// Reads `DWT_CYCCNT`, which wraps around every 2^32 cycles.
+#[inline]
+pub(crate) fn cycle_count() -> u32 {
+    unsafe { ptr::read_volatile(DWT_CYCCNT) }
+}
@@ -0,0 +1,34 @@
+//! The counter, exported to C.
+
+/// Returns [`now()`](crate::now), for C code benchmarked together with Rust code to read the
+/// same counter. Requires the `ffi` feature.
+///
+/// The symbol is exported unmangled with the C calling convention of the target, so declare it as
+///
+/// ```c
+/// #include <stdint.h>
+///
+/// uint64_t cpb_cycle_count(void);
+/// ```
+///
+/// and link the C side against the Rust library of the benchmark, e.g. a `staticlib` that depends
+/// on this crate, or build the C library into the Rust benchmark with the `cc` crate, where the
+/// symbol is resolved when the benchmark is linked. The function takes no arguments, cannot fail
+/// and has no side effects, and it is safe to call from any thread. Values read on either side
+/// are on the same clock and can be subtracted from one another, with the same caveats about
+/// migrating between cores as `now()`.
+///
+/// ```rust
+/// // Links the crate that defines the symbol.
+/// use criterion_cycles_per_byte as _;
+///
+/// extern "C" {
+///     fn cpb_cycle_count() -> u64;
+/// }
+///
+/// assert_ne!(unsafe { cpb_cycle_count() }, 0);
+/// ```
+#[no_mangle]
+pub extern "C" fn cpb_cycle_count() -> u64 {
+    crate::now()
+}
@@ -0,0 +1,106 @@
+//! Results written in the JSON format of google/benchmark, for tooling that already reads it.
+//!
+//! The file has the shape of `--benchmark_format=json` output: a `context` object describing the
+//! machine and a `benchmarks` array with one run per benchmark id, whose user counters `cycles`
+//! and `cpb` sit next to the fields google/benchmark always writes, see
+//! [`CyclesPerByte::save_google_benchmark`](crate::CyclesPerByte::save_google_benchmark).
+
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+#[derive(Serialize)]
+struct Report {
+    context: Context,
+    benchmarks: Vec<Run>,
+}
+
+#[derive(Serialize)]
+struct Context {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mhz_per_cpu: Option<u64>,
+    library_build_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+}
+
+/// One benchmark id, as a google/benchmark run.
+#[derive(Serialize)]
+pub(crate) struct Run {
+    name: String,
+    run_name: String,
+    run_type: &'static str,
+    repetitions: u32,
+    repetition_index: u32,
+    threads: u32,
+    iterations: u64,
+    real_time: f64,
+    cpu_time: f64,
+    time_unit: &'static str,
+    cycles: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpb: Option<f64>,
+}
+
+impl Run {
+    /// Describes `id`, measured over `iterations` samples at `cycles` per iteration, at `hz` if
+    /// known.
+    pub(crate) fn new(
+        id: String,
+        iterations: u64,
+        cycles: f64,
+        cpb: Option<f64>,
+        hz: Option<u64>,
+    ) -> Run {
+        let nanoseconds = hz.map_or(0.0, |hz| cycles / hz as f64 * 1e9);
+        Run {
+            run_name: id.clone(),
+            name: id,
+            run_type: "iteration",
+            repetitions: 1,
+            repetition_index: 0,
+            threads: 1,
+            iterations,
+            real_time: nanoseconds,
+            cpu_time: nanoseconds,
+            time_unit: "ns",
+            cycles,
+            cpb,
+        }
+    }
+}
+
+/// Writes `benchmarks`, measured at `commit` on `machine` with a counter of `hz`, to `path`,
+/// replacing the file.
+pub(crate) fn write(
+    path: &Path,
+    commit: Option<String>,
+    machine: Option<String>,
+    hz: Option<u64>,
+    benchmarks: Vec<Run>,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(
+        &mut file,
+        &Report {
+            context: Context {
+                host_name: machine,
+                mhz_per_cpu: hz.map(|hz| (hz + 500_000) / 1_000_000),
+                library_build_type: if cfg!(debug_assertions) {
+                    "debug"
+                } else {
+                    "release"
+                },
+                commit,
+            },
+            benchmarks,
+        },
+    )?;
+    file.write_all(b"\n")?;
+    file.flush()
+}
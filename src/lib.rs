@@ -1,6 +1,8 @@
 //! `CyclesPerByte` measures clock cycles using the x86 or x86_64 `rdtsc` instruction.
 //!
 //! ```rust
+//! # #[cfg(feature = "criterion")]
+//! # {
 //! # fn fibonacci_slow(_: usize) {}
 //! # fn fibonacci_fast(_: usize) {}
 //! use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
@@ -19,114 +21,280 @@
 //!
 //! criterion_group!(
 //!     name = my_bench;
-//!     config = Criterion::default().with_measurement(CyclesPerByte);
+//!     config = Criterion::default().with_measurement(CyclesPerByte::new());
 //!     targets = bench
 //! );
 //! criterion_main!(my_bench);
+//! # }
 //! ```
+//!
+//! # Migrating from 0.4
+//!
+//! `CyclesPerByte` is no longer a unit struct, as it now carries its configuration, so
+//! `with_measurement(CyclesPerByte)` becomes `with_measurement(CyclesPerByte::new())` (or
+//! `CyclesPerByte::default()`), which still reads plain `rdtsc` like 0.4.
+//!
+//! # Unhalted core cycles
+//!
+//! `rdtsc` counts *reference* cycles: it ticks at a constant rate regardless of the actual core
+//! frequency and keeps ticking while the core is halted. With the `msr` feature enabled on Linux,
+//! `Backend::FixedCounter` instead reads `IA32_FIXED_CTR1`, which counts the core cycles that
+//! were actually spent executing.
+//!
+//! ```rust,no_run
+//! # #[cfg(all(feature = "msr", target_os = "linux"))]
+//! # {
+//! use criterion_cycles_per_byte::{Backend, CyclesPerByte};
+//!
+//! let measurement = CyclesPerByte::new().pinned().backend(Backend::FixedCounter);
+//! if measurement.active_backend() != Backend::FixedCounter {
+//!     eprintln!("no msr access, measuring with rdtsc");
+//! }
+//! # }
+//! ```
+//!
+//! The counter is read through the `msr` driver, which needs some setup:
+//!
+//! - Load the driver with `modprobe msr`.
+//! - Run as root, or grant the binary `CAP_SYS_RAWIO` (`setcap cap_sys_rawio=ep <binary>`) and
+//!   read/write access to `/dev/cpu/*/msr`.
+//! - On Linux 5.9 and later, MSR writes from user space are filtered unless the kernel is booted
+//!   with `msr.allow_writes=on` (or the parameter is set in `/sys/module/msr/parameters`).
+//!   Enabling the counter writes `IA32_FIXED_CTR_CTRL` and `IA32_PERF_GLOBAL_CTRL`.
+//! - Pin the benchmark to a single CPU, e.g. `taskset -c 2 cargo bench` or
+//!   [`CyclesPerByte::pinned`] before selecting the backend. The counter of the CPU the thread is
+//!   running on when the backend is selected is the one that is read, so a thread migrating to
+//!   another CPU would measure the wrong core.
+//! - The kernel's NMI watchdog and `perf` may also program the fixed counters. Disable the
+//!   watchdog (`sysctl kernel.nmi_watchdog=0`) and avoid running `perf` alongside the benchmark.
+//!
+//! If opening the device or enabling the counter fails, a warning is printed and the measurement
+//! falls back to `rdtsc`. Each read is a system call costing on the order of a microsecond, so this
+//! backend is only meaningful for regions much longer than that.
+//!
+//! The `rdpmc` feature adds `Backend::Rdpmc`, which reads the same counter with the `rdpmc`
+//! instruction, without a system call and at close to the cost of `rdtsc`. It needs:
+//!
+//! - User-mode `rdpmc`, i.e. `CR4.PCE` set on every CPU. Linux sets it for all tasks after
+//!   `echo 2 > /sys/bus/event_source/devices/cpu/rdpmc` (`cpu_core` and `cpu_atom` on hybrid
+//!   processors). The default of 1 only allows it in tasks that have mmapped a perf event, which
+//!   this crate does not do. Executing `rdpmc` without `CR4.PCE` crashes the process, so the
+//!   setting is checked first and the instruction is not executed unless it is 2.
+//! - Fixed counter 1 counting in user mode, which nothing enables by default. Selecting
+//!   `Backend::FixedCounter` once with the `msr` feature does, as does setting bits 4 and 5 of
+//!   `IA32_FIXED_CTR_CTRL` (`0x38d`) and bit 33 of `IA32_PERF_GLOBAL_CTRL` (`0x38f`) on every CPU,
+//!   e.g. with `wrmsr -a` from msr-tools. The same caveats about the NMI watchdog and `perf`
+//!   apply.
+//!
+//! The counter is per core like the msr one, so pin the benchmark. If either prerequisite is
+//! missing, a warning is printed and the measurement falls back to `rdtsc`.
+//!
+//! # Energy
+//!
+//! With the `rapl` feature on Linux, `EnergyPerByte` is a second measurement that reads the RAPL
+//! energy counters of the powercap driver instead of cycles and reports `µJ/byte`.
+//!
+//! # Without criterion
+//!
+//! The `criterion` feature (enabled by default) provides the [`Measurement`] implementation. With
+//! `default-features = false, features = ["std"]` the crate has no dependency on criterion and
+//! only exposes the counter itself: the raw [`now()`] and [`CyclesPerByte`] with its backend
+//! selection, read via [`CyclesPerByte::read`] or around a closure with
+//! [`CyclesPerByte::measure`], and the probes in [`detect`].
+//!
+//! Without the `std` feature the crate is `no_std` and only provides [`now()`]. The
+//! `critical-section` feature adds `measure_critical`, which measures a closure with interrupts
+//! disabled through the [`critical-section`] crate, for bare-metal targets where an interrupt
+//! handler running mid-measurement would otherwise be counted.
+//!
+//! `cargo run --release --example selftest` prints what [`detect`] finds on the current machine
+//! and fails if the counter looks broken.
+//!
+//! ```rust
+//! use criterion_cycles_per_byte::now;
+//!
+//! let start = now();
+//! let delta = now().saturating_sub(start);
+//! # let _ = delta;
+//! ```
+//!
+//! # Cortex-M
+//!
+//! On bare-metal ARM targets (`thumbv7m`, `thumbv7em` and `thumbv8m.main`) [`now()`] reads the
+//! cycle counter of the Data Watchpoint and Trace unit, `DWT_CYCCNT`, and the crate is used
+//! without the `std` feature. The trace unit is off out of reset, so call
+//! [`enable_dwt_cyccnt`] once at startup; it sets `DEMCR.TRCENA` and `DWT_CTRL.CYCCNTENA`. Cores
+//! without the counter, i.e. Cortex-M0, M0+ and M23, read zero.
+//!
+//! ```rust,ignore
+//! use criterion_cycles_per_byte::{enable_dwt_cyccnt, measure_critical};
+//!
+//! enable_dwt_cyccnt();
+//! let cycles = measure_critical(|| aes.encrypt_block(&mut block));
+//! ```
+//!
+//! [`Measurement`]: https://docs.rs/criterion/0.4/criterion/measurement/trait.Measurement.html
+//! [`critical-section`]: https://docs.rs/critical-section/1
+
+#![cfg_attr(not(feature = "std"), no_std)]
+// The crate documentation refers to items that only exist with `std`; docs.rs builds with all
+// features.
+#![cfg_attr(not(feature = "std"), allow(rustdoc::broken_intra_doc_links))]
+
+// Documentation builds (e.g. docs.rs on other targets) get non-functional stubs instead, see
+// `rdtsc` below.
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    all(target_arch = "arm", target_os = "none"),
+    doc
+)))]
+compile_error!(
+    "criterion-cycles-per-byte currently relies on x86, x86_64 or the Cortex-M DWT cycle counter."
+);
+
+#[cfg(feature = "std")]
+mod affinity;
+#[cfg(feature = "serde")]
+mod baseline;
+#[cfg(feature = "std")]
+mod binary;
+#[cfg(feature = "critical-section")]
+mod critical;
+#[cfg(feature = "std")]
+pub mod detect;
+#[cfg(any(all(target_arch = "arm", target_os = "none"), doc))]
+mod dwt;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(feature = "serde")]
+mod google_benchmark;
+#[cfg(feature = "std")]
+mod manual;
+#[cfg(feature = "std")]
+mod measurement;
+#[cfg(feature = "std")]
+pub mod metadata;
+#[cfg(all(feature = "msr", target_os = "linux"))]
+mod msr;
+#[cfg(all(feature = "numa", target_os = "linux"))]
+mod numa;
+#[cfg(all(feature = "rapl", target_os = "linux"))]
+mod rapl;
+#[cfg(all(feature = "rdpmc", target_os = "linux"))]
+mod rdpmc;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "criterion")]
+mod wall;
 
-use criterion::{
-    measurement::{Measurement, ValueFormatter},
-    Throughput,
+#[cfg(feature = "serde")]
+pub use baseline::{bisect_regression, read_baseline_series, RegressedCommit, Regression};
+#[cfg(feature = "std")]
+pub use binary::{read_binary, read_binary_header, DumpHeader};
+#[cfg(feature = "critical-section")]
+pub use critical::measure_critical;
+#[cfg(any(all(target_arch = "arm", target_os = "none"), doc))]
+pub use dwt::enable_dwt_cyccnt;
+#[cfg(feature = "ffi")]
+pub use ffi::cpb_cycle_count;
+#[cfg(feature = "std")]
+pub use manual::{mark, tic, tic_data, tic_setup, toc, toc_data, toc_setup};
+#[cfg(feature = "std")]
+use measurement::Source;
+#[cfg(feature = "std")]
+pub use measurement::{
+    Backend, ConfigError, CyclesPerByte, Discarded, FrequencySource, SubtractionMode,
+    ValidationReport,
 };
+#[cfg(all(feature = "numa", target_os = "linux"))]
+pub use numa::bind_to_numa_node;
+#[cfg(all(feature = "rapl", target_os = "linux"))]
+pub use rapl::EnergyPerByte;
+#[cfg(feature = "std")]
+pub use stats::CycleStats;
+#[cfg(feature = "criterion")]
+pub use wall::{CyclesAndNanos, CyclesAndWallTime};
 
-#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
-compile_error!("criterion-cycles-per-byte currently relies on x86 or x86_64.");
+#[cfg(target_arch = "x86")]
+use core::arch::x86 as arch;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64 as arch;
+/// Reads the time-stamp counter with `rdtsc`.
+///
+/// On Cortex-M this reads `DWT_CYCCNT` instead, which only counts once [`enable_dwt_cyccnt`] has
+/// been called. It is 32 bits wide and wraps around after 2^32 cycles, about 21 seconds at
+/// 200 MHz, so take the difference of two reads as `(end as u32).wrapping_sub(start as u32)`.
+#[inline]
+pub fn now() -> u64 {
+    #[cfg(all(target_arch = "arm", target_os = "none"))]
+    {
+        u64::from(dwt::cycle_count())
+    }
 
-/// `CyclesPerByte` measures clock cycles using the x86 or x86_64 `rdtsc` instruction. `cpb` is
-/// the preferred measurement for cryptographic algorithms.
-pub struct CyclesPerByte;
+    #[cfg(not(all(target_arch = "arm", target_os = "none")))]
+    rdtsc()
+}
 
 // WARN: does not check for the cpu feature; but we'd panic anyway so...
+#[cfg(not(all(target_arch = "arm", target_os = "none")))]
+#[inline]
 fn rdtsc() -> u64 {
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
     unsafe {
-        core::arch::x86_64::_rdtsc()
+        arch::_rdtsc()
     }
 
-    #[cfg(target_arch = "x86")]
-    unsafe {
-        core::arch::x86::_rdtsc()
-    }
+    // Only compiled for documentation builds, everything else hits the `compile_error!` above.
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+    0
 }
 
-impl Measurement for CyclesPerByte {
-    type Intermediate = u64;
-    type Value = u64;
-
-    fn start(&self) -> Self::Intermediate {
-        rdtsc()
-    }
-
-    fn end(&self, i: Self::Intermediate) -> Self::Value {
-        rdtsc().saturating_sub(i)
-    }
-
-    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
-        v1 + v2
-    }
-
-    fn zero(&self) -> Self::Value {
-        0
-    }
-
-    fn to_f64(&self, value: &Self::Value) -> f64 {
-        *value as f64
+/// `lfence; rdtsc; lfence`: earlier instructions complete before the counter is read, and later
+/// ones do not start until it has been.
+#[cfg(feature = "std")]
+#[inline]
+fn rdtsc_serialized() -> u64 {
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    unsafe {
+        arch::_mm_lfence();
+        let tsc = arch::_rdtsc();
+        arch::_mm_lfence();
+        tsc
     }
 
-    fn formatter(&self) -> &dyn ValueFormatter {
-        &CyclesPerByteFormatter
-    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+    0
 }
 
-struct CyclesPerByteFormatter;
-
-impl ValueFormatter for CyclesPerByteFormatter {
-    fn format_value(&self, value: f64) -> String {
-        format!("{:.4} cycles", value)
-    }
-
-    fn format_throughput(&self, throughput: &Throughput, value: f64) -> String {
-        match throughput {
-            Throughput::Bytes(b) => format!("{:.4} cpb", value / *b as f64),
-            Throughput::Elements(b) => format!("{:.4} cycles/{}", value, b),
-            Throughput::BytesDecimal(b) => format!("{:.4} cpb (decimal)", value / *b as f64),
-        }
+/// Reads `TSC_AUX`, which the operating system sets to an identifier of the current core.
+#[cfg(feature = "std")]
+#[inline]
+fn tsc_aux() -> Option<u32> {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let mut aux = 0;
+        arch::__rdtscp(&mut aux);
+        Some(aux)
     }
 
-    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
-        "cycles"
-    }
+    #[cfg(not(target_arch = "x86_64"))]
+    None
+}
 
-    fn scale_throughputs(
-        &self,
-        _typical_value: f64,
-        throughput: &Throughput,
-        values: &mut [f64],
-    ) -> &'static str {
-        match throughput {
-            Throughput::Bytes(n) => {
-                for val in values {
-                    *val /= *n as f64;
-                }
-                "cpb"
-            }
-            Throughput::Elements(n) => {
-                for val in values {
-                    *val /= *n as f64;
-                }
-                "c/e"
-            }
-            Throughput::BytesDecimal(n) => {
-                for val in values {
-                    *val /= *n as f64;
-                }
-                "cpb (decimal)"
-            },
-        }
+/// `rdtscp; lfence`: `rdtscp` waits for earlier instructions to complete, and the fence keeps
+/// later ones from starting before the counter is read.
+#[cfg(feature = "std")]
+#[inline]
+fn rdtscp_serialized() -> u64 {
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    unsafe {
+        let mut aux = 0;
+        let tsc = arch::__rdtscp(&mut aux);
+        arch::_mm_lfence();
+        tsc
     }
 
-    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
-        "cycles"
-    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+    0
 }
@@ -0,0 +1,361 @@
+//! Manually marked sub-regions of a benchmark iteration.
+
+use crate::Source;
+use std::cell::RefCell;
+
+struct Manual {
+    /// The counter of the measurement whose region is in progress on this thread.
+    source: Source,
+    data: Bucket,
+    setup: Bucket,
+    marks: Marks,
+}
+
+/// The sub-regions of one kind since the measured region started.
+struct Bucket {
+    /// The counter value at the last tic.
+    start: Option<u64>,
+    /// The sum of all completed regions.
+    total: u64,
+    /// The number of completed regions.
+    regions: u64,
+}
+
+impl Bucket {
+    const EMPTY: Bucket = Bucket {
+        start: None,
+        total: 0,
+        regions: 0,
+    };
+
+    #[inline]
+    fn toc(&mut self, end: u64) {
+        if let Some(start) = self.start.take() {
+            self.total = self.total.saturating_add(end.saturating_sub(start));
+            self.regions += 1;
+        }
+    }
+}
+
+/// The marks of the region in progress.
+struct Marks {
+    /// The counter value at the last mark, if any.
+    last: Option<u64>,
+    /// The label of the last mark, kept to reuse its allocation.
+    label: String,
+    /// Every pair of consecutive labels marked on this thread, and their cycles in the region in
+    /// progress. Pairs stay once seen, so later regions marking the same phases do not allocate.
+    phases: Vec<Phase>,
+}
+
+struct Phase {
+    from: String,
+    to: String,
+    cycles: u64,
+    /// Whether the pair was marked in the region in progress.
+    marked: bool,
+}
+
+impl Marks {
+    #[inline]
+    fn mark(&mut self, label: &str, now: u64) {
+        if let Some(start) = self.last {
+            let cycles = now.saturating_sub(start);
+            let from = &self.label;
+            match self
+                .phases
+                .iter_mut()
+                .find(|phase| phase.from == *from && phase.to == label)
+            {
+                Some(phase) => {
+                    phase.cycles = phase.cycles.saturating_add(cycles);
+                    phase.marked = true;
+                }
+                None => self.phases.push(Phase {
+                    from: from.clone(),
+                    to: label.to_owned(),
+                    cycles,
+                    marked: true,
+                }),
+            }
+        }
+        self.label.clear();
+        self.label.push_str(label);
+        self.last = Some(now);
+    }
+}
+
+/// What was marked during one measured region.
+pub(crate) struct Marked {
+    /// The cycles of the data regions, if any sub-region was marked at all.
+    pub(crate) data: Option<u64>,
+    pub(crate) data_regions: u64,
+    pub(crate) setup_cycles: u64,
+    pub(crate) setup_regions: u64,
+}
+
+thread_local! {
+    static MANUAL: RefCell<Manual> = const {
+        RefCell::new(Manual {
+            source: Source::Rdtsc,
+            data: Bucket::EMPTY,
+            setup: Bucket::EMPTY,
+            marks: Marks {
+                last: None,
+                label: String::new(),
+                phases: Vec::new(),
+            },
+        })
+    };
+}
+
+/// Starts a manually marked sub-region of the benchmark iteration.
+///
+/// By default, a measurement covers the whole benchmark closure, including any setup in it like
+/// generating random input. When `tic()` and [`toc()`] are called inside the closure, the
+/// measurement instead reports the sum of all the cycles between each `tic()` and the following
+/// `toc()`, and nothing else:
+///
+/// ```rust
+/// # #[cfg(feature = "criterion")]
+/// # {
+/// # fn fill_random(buf: &mut [u8]) { buf.iter_mut().for_each(|b| *b = 7); }
+/// # fn hash(buf: &[u8]) -> u8 { buf.iter().fold(0, |a, b| a ^ b) }
+/// use criterion::{black_box, Criterion};
+/// use criterion_cycles_per_byte::{tic, toc, CyclesPerByte};
+///
+/// fn bench(c: &mut Criterion<CyclesPerByte>) {
+///     let mut buf = [0u8; 1024];
+///     c.bench_function("hash", |b| {
+///         b.iter(|| {
+///             fill_random(&mut buf);
+///             tic();
+///             let digest = hash(black_box(&buf));
+///             toc();
+///             digest
+///         })
+///     });
+/// }
+/// # }
+/// ```
+///
+/// The result is the marked cycles per iteration, read from the counter of the measurement whose
+/// region is in progress on this thread, or `rdtsc` outside of one. Calling `tic()` again before
+/// [`toc()`] restarts the sub-region.
+#[inline]
+pub fn tic() {
+    tic_data();
+}
+
+/// Ends the sub-region started by the last [`tic()`], adding its cycles to the current sample.
+///
+/// Does nothing if there is no `tic()` to pair with.
+#[inline]
+pub fn toc() {
+    toc_data();
+}
+
+/// Starts a data sub-region, the same as [`tic()`].
+///
+/// Together with [`tic_setup()`], this splits an iteration into the work on the input, whose
+/// cycles are the measured value and thus the cpb, and a fixed setup like a key schedule that
+/// the work is amortized over. The setup is reported separately as the average cycles per setup
+/// region, e.g. `2.0100 cpb + 340 cyc setup`:
+///
+/// ```rust
+/// # #[cfg(feature = "criterion")]
+/// # {
+/// # fn expand_key(key: &[u8; 16]) -> [u32; 44] { [u32::from(key[0]); 44] }
+/// # fn encrypt(keys: &[u32; 44], data: &mut [u8]) { data.iter_mut().for_each(|b| *b ^= keys[0] as u8); }
+/// use criterion::{black_box, Criterion, Throughput};
+/// use criterion_cycles_per_byte::{tic_data, tic_setup, toc_data, toc_setup, CyclesPerByte};
+///
+/// fn bench(c: &mut Criterion<CyclesPerByte>) {
+///     let key = [7u8; 16];
+///     let mut data = [0u8; 4096];
+///     let mut group = c.benchmark_group("aes");
+///     group.throughput(Throughput::Bytes(data.len() as u64));
+///     group.bench_function("expand and encrypt", |b| {
+///         b.iter(|| {
+///             tic_setup();
+///             let keys = expand_key(black_box(&key));
+///             toc_setup();
+///             tic_data();
+///             encrypt(&keys, black_box(&mut data));
+///             toc_data();
+///         })
+///     });
+///     group.finish();
+/// }
+/// # }
+/// ```
+///
+/// As with [`tic()`], each sample's value is the sum of its data regions and nothing else as soon
+/// as any sub-region, data or setup, is marked in it; a sample with only setup regions measures 0
+/// cycles. The setup regions, on the other hand, accumulate over all samples including warm-up,
+/// and over every benchmark measured with the measurement and its clones, until
+/// [`CyclesPerByte::clear_samples`](crate::CyclesPerByte::clear_samples) resets them. Call it on
+/// a clone kept aside between benchmarks that mark setup regions.
+#[inline]
+pub fn tic_data() {
+    MANUAL.with(|manual| {
+        let manual = &mut *manual.borrow_mut();
+        manual.data.start = Some(manual.source.read());
+    });
+}
+
+/// Ends the data sub-region started by the last [`tic_data()`], the same as [`toc()`].
+#[inline]
+pub fn toc_data() {
+    MANUAL.with(|manual| {
+        let manual = &mut *manual.borrow_mut();
+        let end = manual.source.read_end();
+        manual.data.toc(end);
+    });
+}
+
+/// Starts a setup sub-region, see [`tic_data()`].
+#[inline]
+pub fn tic_setup() {
+    MANUAL.with(|manual| {
+        let manual = &mut *manual.borrow_mut();
+        manual.setup.start = Some(manual.source.read());
+    });
+}
+
+/// Ends the setup sub-region started by the last [`tic_setup()`].
+#[inline]
+pub fn toc_setup() {
+    MANUAL.with(|manual| {
+        let manual = &mut *manual.borrow_mut();
+        let end = manual.source.read_end();
+        manual.setup.toc(end);
+    });
+}
+
+/// Records the counter at a point labelled `label` of the benchmark iteration, for a breakdown of
+/// its cycles into phases.
+///
+/// The cycles between each mark and the next one in the same measured region are added up per
+/// pair of labels, e.g. `parse -> hash`, and reported by
+/// [`CyclesPerByte::phase_report`](crate::CyclesPerByte::phase_report). Unlike [`tic()`] and
+/// [`toc()`], marks do not change the measured value, and the phases are a flat sequence rather
+/// than nested regions:
+///
+/// ```rust
+/// use criterion_cycles_per_byte::{mark, CyclesPerByte};
+///
+/// let measurement = CyclesPerByte::new();
+/// measurement.measure(|| {
+///     mark("load");
+///     mark("hash");
+///     mark("store");
+/// });
+/// let phases: Vec<_> = measurement.phase_report().into_iter().map(|(phases, _)| phases).collect();
+/// assert_eq!(phases, ["load -> hash", "hash -> store"]);
+/// ```
+///
+/// Marks read the counter of the region in progress, as [`tic()`] does, and do not pair across
+/// regions: the cycles from the last mark to the end of a region and from its start to the first
+/// mark are in no phase. Each mark also compares its label with the pairs seen so far and
+/// allocates the first time a pair occurs on a thread, which costs far more than [`tic()`]; the
+/// phases include the cost of their closing mark.
+#[inline]
+pub fn mark(label: &str) {
+    MANUAL.with(|manual| {
+        let manual = &mut *manual.borrow_mut();
+        let now = manual.source.read();
+        manual.marks.mark(label, now);
+    });
+}
+
+/// Forgets any sub-regions and makes [`tic`] and [`toc`] read `source`, at the start of a sample.
+pub(crate) fn reset(source: &Source) {
+    MANUAL.with(|manual| {
+        let mut manual = manual.borrow_mut();
+        manual.source = source.clone();
+        manual.data = Bucket::EMPTY;
+        manual.setup = Bucket::EMPTY;
+        manual.marks.last = None;
+    });
+}
+
+/// Returns what was marked since the last [`reset`], and forgets it.
+pub(crate) fn take() -> Marked {
+    MANUAL.with(|manual| {
+        let mut manual = manual.borrow_mut();
+        let data = std::mem::replace(&mut manual.data, Bucket::EMPTY);
+        let setup = std::mem::replace(&mut manual.setup, Bucket::EMPTY);
+        Marked {
+            data: Some(data.total).filter(|_| data.regions != 0 || setup.regions != 0),
+            data_regions: data.regions,
+            setup_cycles: setup.total,
+            setup_regions: setup.regions,
+        }
+    })
+}
+
+/// Calls `f` with the labels and cycles of each phase marked since the last [`reset`], and
+/// forgets them.
+pub(crate) fn take_phases<F: FnMut(&str, &str, u64)>(mut f: F) {
+    MANUAL.with(|manual| {
+        let mut manual = manual.borrow_mut();
+        for phase in manual.marks.phases.iter_mut().filter(|phase| phase.marked) {
+            f(&phase.from, &phase.to, phase.cycles);
+            phase.cycles = 0;
+            phase.marked = false;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::scripted;
+
+    #[test]
+    fn setup_regions_are_kept_apart_from_data() {
+        // One region of two iterations, each with 340 cycles of setup and 128 of data.
+        let measurement = scripted(&[0, 1000, 1340, 1400, 1528, 2000, 2340, 2400, 2528, 3000]);
+        let cycles = measurement.measure(|| {
+            for _ in 0..2 {
+                tic_setup();
+                toc_setup();
+                tic_data();
+                toc_data();
+            }
+        });
+        assert_eq!(cycles, 256);
+        assert_eq!(measurement.setup_cycles(), Some(340.0));
+        #[cfg(feature = "criterion")]
+        {
+            use criterion::{measurement::Measurement, Throughput};
+            assert_eq!(
+                measurement
+                    .formatter()
+                    .format_throughput(&Throughput::Bytes(64), 128.0),
+                "2.0000 cpb + 340 cyc setup"
+            );
+        }
+    }
+
+    #[test]
+    fn marks_add_up_phases_across_regions() {
+        // Two regions, each reading the counter at the start, at three marks and at the end.
+        let measurement = scripted(&[0, 10, 110, 150, 200, 1000, 1010, 1130, 1160, 1200]);
+        for _ in 0..2 {
+            measurement.measure(|| {
+                mark("load");
+                mark("hash");
+                mark("store");
+            });
+        }
+        assert_eq!(
+            measurement.phase_report(),
+            [
+                ("load -> hash".to_owned(), 220),
+                ("hash -> store".to_owned(), 70)
+            ]
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! How criterion prints the values of [`CyclesPerByte`](super::CyclesPerByte).
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use super::state::PmuEvent;
+use super::state::{Discards, Recording, Setup, Tracked};
+use crate::{metadata, stats};
+use criterion::{measurement::ValueFormatter, Throughput};
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use std::sync::atomic::Ordering;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone)]
+pub(super) struct CyclesPerByteFormatter {
+    pub(super) decimal_suffix: bool,
+    pub(super) relative_marker: bool,
+    pub(super) combined_units: bool,
+    pub(super) thousands_separator: Option<char>,
+    /// The statistics to annotate throughput results with.
+    pub(super) variation: Option<Arc<Mutex<stats::Streaming>>>,
+    /// The statistics to annotate cpb results with their 99th percentile from.
+    pub(super) p99: Option<Arc<Mutex<stats::Streaming>>>,
+    /// The discard counts to check before reporting.
+    pub(super) discards: Option<Arc<Discards>>,
+    /// The setup regions to report alongside throughput results.
+    pub(super) setup: Arc<Setup>,
+    /// Where to file the cpb of each result.
+    pub(super) tracked: Option<Arc<Tracked>>,
+    /// Bytes per block for `c/blk` results, 0 for none.
+    pub(super) block_size: u64,
+    /// Whether to report element throughputs as SI-scaled cycles per operation.
+    pub(super) per_operation: bool,
+    /// Whether to print bare numbers without units.
+    pub(super) compact: bool,
+    /// Whether to add the cpb of byte counts inferred from the benchmark id to cycle counts.
+    pub(super) bytes_from_id: bool,
+    /// Whether a frequency was selected with [`CyclesPerByte::frequency_source`].
+    pub(super) frequency_configured: bool,
+    /// The lower bound to compare cpb results with.
+    pub(super) theoretical_cpb: Option<f64>,
+    /// The `(min, max)` cpb outside of which results are flagged.
+    pub(super) plausible_cpb: Option<(f64, f64)>,
+    /// The recording to take the median from for implausible results.
+    pub(super) recording: Option<Arc<Mutex<Recording>>>,
+    /// The event to report `cycles/event` for.
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub(super) pmu: Option<Arc<PmuEvent>>,
+}
+
+impl CyclesPerByteFormatter {
+    /// Checks the discarded samples of the benchmark being reported, warning if there were too
+    /// many and keeping the first such benchmark for [`CyclesPerByte::check_discards`].
+    fn check_discards(&self) {
+        if let Some(ref discards) = self.discards {
+            if let Err(exceeded) = discards.check() {
+                eprintln!(
+                    "criterion-cycles-per-byte: {} of {} samples were discarded ({:.1}%), more than the allowed {:.1}%",
+                    exceeded.discarded,
+                    exceeded.samples,
+                    exceeded.fraction() * 100.0,
+                    discards.max_fraction * 100.0
+                );
+                discards.exceeded.lock().unwrap().get_or_insert(exceeded);
+            }
+        }
+    }
+
+    /// Files the cpb of `value` under the current benchmark id, if tracking and the result is
+    /// per byte.
+    fn track(&self, throughput: &Throughput, value: f64) {
+        let tracked = match self.tracked {
+            Some(ref tracked) => tracked,
+            None => return,
+        };
+        let bytes = result_bytes(throughput);
+        if bytes == 0 {
+            return;
+        }
+        if let Some(id) = metadata::id() {
+            tracked.push(id, bytes, value / bytes as f64);
+        }
+    }
+
+    /// Formats a cycle count, without the cpb of [`CyclesPerByte::bytes_from_id`].
+    fn format_cycles(&self, value: f64) -> String {
+        let mut formatted = format!("{:.4}", value);
+        if let Some(separator) = self.thousands_separator {
+            formatted = group_thousands(&formatted, separator);
+        }
+        if self.compact {
+            return formatted;
+        }
+        format!("{} {}", formatted, self.cycles_label())
+    }
+
+    fn cycles_label(&self) -> &'static str {
+        match (
+            counter_unit(std::env::consts::ARCH, self.frequency_configured),
+            self.relative_marker,
+        ) {
+            (unit, false) => unit,
+            ("ticks", true) => "ticks*",
+            ("cycles (est.)", true) => "cycles* (est.)",
+            (_, true) => "cycles*",
+        }
+    }
+
+    fn cpb_label(&self, decimal: bool) -> &'static str {
+        match (self.relative_marker, decimal && self.decimal_suffix) {
+            (false, false) => "cpb",
+            (false, true) => "cpb (decimal)",
+            (true, false) => "cpb*",
+            (true, true) => "cpb* (decimal)",
+        }
+    }
+
+    fn per_element_label(&self) -> &'static str {
+        if self.relative_marker {
+            "c/e*"
+        } else {
+            "c/e"
+        }
+    }
+
+    fn per_block_label(&self) -> &'static str {
+        if self.relative_marker {
+            "c/blk*"
+        } else {
+            "c/blk"
+        }
+    }
+
+    /// Returns the median of the recorded deltas of the current benchmark id, or of all of them
+    /// without an id, if recording.
+    fn recorded_median(&self) -> Option<f64> {
+        let recording = self.recording.as_ref()?.lock().unwrap();
+        metadata::with_id(|id| match id {
+            Some(id) => stats::median(recording.by_id.get(id)?),
+            None => {
+                let deltas: Vec<u64> = recording.timeline.iter().map(|&(_, d)| d).collect();
+                stats::median(&deltas)
+            }
+        })
+    }
+
+    /// Checks the cpb of `value` per byte of `bytes` against the plausible range, returning the
+    /// value to report instead and the annotation saying so.
+    fn check_plausible(&self, bytes: u64, value: f64) -> (f64, Option<String>) {
+        let (min, max) = match self.plausible_cpb {
+            Some(range) => range,
+            None => return (value, None),
+        };
+        let cpb = value / bytes as f64;
+        if (min..=max).contains(&cpb) {
+            return (value, None);
+        }
+        eprintln!(
+            "criterion-cycles-per-byte: {}{:.4} cpb is outside the plausible range {}..{}",
+            metadata::id().map_or_else(String::new, |id| format!("{}: ", id)),
+            cpb,
+            min,
+            max
+        );
+        match self.recorded_median() {
+            Some(median) => (median, Some(format!("(median, measured {:.4} cpb)", cpb))),
+            None => (value, Some("(implausible)".to_owned())),
+        }
+    }
+
+    /// Formats `value` per byte of `bytes`, and per block and relative to the bound if enabled.
+    fn format_bytes(&self, bytes: u64, value: f64, decimal: bool) -> String {
+        let (value, flag) = self.check_plausible(bytes, value);
+        let cpb = value / bytes as f64;
+        let mut formatted = format!("{:.4} {}", cpb, self.cpb_label(decimal));
+        if let Some(flag) = flag {
+            formatted = format!("{} {}", formatted, flag);
+        }
+        if self.block_size != 0 {
+            let blocks = bytes.div_ceil(self.block_size);
+            formatted = format!(
+                "{} ({:.4} {})",
+                formatted,
+                value / blocks as f64,
+                self.per_block_label()
+            );
+        }
+        match self.theoretical_cpb {
+            Some(bound) if cpb > 0.0 => {
+                if cpb < bound {
+                    format!("{} (beyond peak)", formatted)
+                } else {
+                    format!("{} ({:.0}% of peak)", formatted, bound / cpb * 100.0)
+                }
+            }
+            _ => formatted,
+        }
+    }
+
+    /// Returns the number [`CyclesPerByteFormatter::format_rate`] puts first.
+    fn rate_value(&self, throughput: &Throughput, value: f64) -> f64 {
+        if let Some(m) = metadata::get().filter(|m| m.bytes != 0 || m.elements != 0) {
+            let count = if m.bytes != 0 { m.bytes } else { m.elements };
+            return value / count as f64;
+        }
+        match throughput {
+            Throughput::Bytes(b) | Throughput::BytesDecimal(b) => value / *b as f64,
+            Throughput::Elements(b) if self.combined_units || self.per_operation => {
+                if *b == 0 {
+                    value
+                } else {
+                    value / *b as f64
+                }
+            }
+            Throughput::Elements(_) => value,
+        }
+    }
+
+    /// Formats `value` per unit of throughput, without the total cycle count.
+    fn format_rate(&self, throughput: &Throughput, value: f64) -> String {
+        if let Some(m) = metadata::get().filter(|m| m.bytes != 0 || m.elements != 0) {
+            return match (m.bytes, m.elements) {
+                (0, elements) if self.per_operation => {
+                    format_si(value / elements as f64, &format!("cyc/{}", m.unit))
+                }
+                (0, elements) => format!("{:.4} cycles/{}", value / elements as f64, m.unit),
+                (bytes, 0) => self.format_bytes(bytes, value, false),
+                (bytes, elements) => format!(
+                    "{:.4} {} ({:.4} c/{})",
+                    value / bytes as f64,
+                    self.cpb_label(false),
+                    value / elements as f64,
+                    m.unit
+                ),
+            };
+        }
+
+        match throughput {
+            Throughput::Bytes(b) => self.format_bytes(*b, value, false),
+            Throughput::Elements(0) if self.per_operation => {
+                format!("{} (0 ops)", format_si(value, "cyc"))
+            }
+            Throughput::Elements(b) if self.per_operation => format_si(value / *b as f64, "cyc/op"),
+            Throughput::Elements(0) if self.combined_units => {
+                format!("{:.4} {}", value, self.cycles_label())
+            }
+            Throughput::Elements(b) if self.combined_units => {
+                format!("{:.4} {}", value / *b as f64, self.per_element_label())
+            }
+            Throughput::Elements(b) => format!("{:.4} {}/{}", value, self.cycles_label(), b),
+            Throughput::BytesDecimal(b) => self.format_bytes(*b, value, true),
+        }
+    }
+}
+
+/// Returns the bytes per iteration of a result, from the metadata or `throughput`, or 0 if it is
+/// not per byte.
+pub(super) fn result_bytes(throughput: &Throughput) -> u64 {
+    match metadata::get().filter(|m| m.bytes != 0 || m.elements != 0) {
+        Some(m) => m.bytes,
+        None => match *throughput {
+            Throughput::Bytes(b) | Throughput::BytesDecimal(b) => b,
+            Throughput::Elements(_) => 0,
+        },
+    }
+}
+
+/// Returns what the values of [`now()`](crate::now) are called on `arch`, as named by
+/// [`std::env::consts::ARCH`].
+///
+/// The time-stamp counter of x86 and the DWT counter of Cortex-M count cycles. The generic timer
+/// of aarch64 ticks at a fixed rate unrelated to the core clock, so its values are ticks, unless a
+/// frequency has been configured to relate them to cycles, which are then an estimate.
+pub(super) fn counter_unit(arch: &str, frequency_configured: bool) -> &'static str {
+    if arch != "aarch64" {
+        "cycles"
+    } else if frequency_configured {
+        "cycles (est.)"
+    } else {
+        "ticks"
+    }
+}
+
+/// The SI prefixes of [`CyclesPerByte::per_operation`], by power of 1000.
+pub(super) const SI_PREFIXES: [&str; 5] = ["", "k", "M", "G", "T"];
+
+/// Returns the power of 1000 to scale `value` by for [`CyclesPerByte::per_operation`], so that it
+/// is below 1000 once scaled and rounded to two decimals.
+pub(super) fn si_power(value: f64) -> usize {
+    let mut power = 0;
+    while power + 1 < SI_PREFIXES.len()
+        && (value.abs() / 1000f64.powi(power as i32) * 100.0).round() >= 100_000.0
+    {
+        power += 1;
+    }
+    power
+}
+
+/// Formats `value` with two decimals and the SI prefix that fits it in front of `unit`.
+pub(super) fn format_si(value: f64, unit: &str) -> String {
+    let power = si_power(value);
+    format!(
+        "{:.2} {}{}",
+        value / 1000f64.powi(power as i32),
+        SI_PREFIXES[power],
+        unit
+    )
+}
+
+/// Returns `label` as the `'static` string criterion wants for a scale, leaking each distinct
+/// label once.
+pub(super) fn static_label(label: String) -> &'static str {
+    static LABELS: Mutex<BTreeMap<String, &'static str>> = Mutex::new(BTreeMap::new());
+    let mut labels = LABELS.lock().unwrap();
+    if let Some(&label) = labels.get(&label) {
+        return label;
+    }
+    let leaked = Box::leak(label.clone().into_boxed_str());
+    labels.insert(label, leaked);
+    leaked
+}
+
+/// Inserts `separator` between groups of three digits in the integer part of `number`.
+pub(super) fn group_thousands(number: &str, separator: char) -> String {
+    let (sign, unsigned) = match number.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", number),
+    };
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(point) => unsigned.split_at(point),
+        None => (unsigned, ""),
+    };
+
+    let mut grouped = String::from(sign);
+    for (i, digit) in integer.chars().enumerate() {
+        if i != 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped.push_str(fraction);
+    grouped
+}
+
+impl ValueFormatter for CyclesPerByteFormatter {
+    fn format_value(&self, value: f64) -> String {
+        self.check_discards();
+        let cycles = self.format_cycles(value);
+        if self.compact || !self.bytes_from_id || metadata::get().is_some() {
+            return cycles;
+        }
+        match metadata::with_id(|id| id.and_then(metadata::infer_bytes_from_id)) {
+            Some(bytes) => format!("{} ({})", cycles, self.format_bytes(bytes, value, false)),
+            None => cycles,
+        }
+    }
+
+    fn format_throughput(&self, throughput: &Throughput, value: f64) -> String {
+        self.check_discards();
+        self.track(throughput, value);
+        if self.compact {
+            return format!("{:.4}", self.rate_value(throughput, value));
+        }
+        let mut rate = self.format_rate(throughput, value);
+        if let Some(setup) = self.setup.average() {
+            rate = format!("{} + {:.0} cyc setup", rate, setup);
+        }
+        if let Some(ref variation) = self.variation {
+            if let Some(cv) = variation.lock().unwrap().coefficient_of_variation() {
+                rate = format!("{} (cv={:.1}%)", rate, cv * 100.0);
+            }
+        }
+        if let Some(ref p99) = self.p99 {
+            let stats = p99.lock().unwrap().stats();
+            let bytes = result_bytes(throughput);
+            if stats.count != 0 && bytes != 0 {
+                rate = format!("{} (p99: {:.4})", rate, stats.p99 / bytes as f64);
+            }
+        }
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let Some(ref pmu) = self.pmu {
+            pmu.reported.store(true, Ordering::Relaxed);
+            if let Some(ratio) = pmu.cycles_per_event() {
+                rate = format!("{} ({:.2} cycles/event)", rate, ratio);
+            }
+        }
+        if self.combined_units {
+            format!("{}, {}", self.format_cycles(value), rate)
+        } else {
+            rate
+        }
+    }
+
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        self.check_discards();
+        self.cycles_label()
+    }
+
+    fn scale_throughputs(
+        &self,
+        typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        self.check_discards();
+        if let Some(m) = metadata::get().filter(|m| m.bytes != 0) {
+            for val in values {
+                *val /= m.bytes as f64;
+            }
+            return self.cpb_label(false);
+        }
+        // Labelled like `format_rate`, e.g. `cycles/cp`.
+        if let Some(m) = metadata::get().filter(|m| m.elements != 0) {
+            if !self.per_operation {
+                for val in values {
+                    *val /= m.elements as f64;
+                }
+                return static_label(format!("cycles/{}", m.unit));
+            }
+            let power = si_power(typical_value / m.elements as f64);
+            for val in values {
+                *val /= m.elements as f64 * 1000f64.powi(power as i32);
+            }
+            return static_label(format!("{}cyc/{}", SI_PREFIXES[power], m.unit));
+        }
+
+        match throughput {
+            Throughput::Bytes(n) => {
+                for val in values {
+                    *val /= *n as f64;
+                }
+                self.cpb_label(false)
+            }
+            Throughput::Elements(n) if self.per_operation && *n != 0 => {
+                let power = si_power(typical_value / *n as f64);
+                for val in values {
+                    *val /= *n as f64 * 1000f64.powi(power as i32);
+                }
+                ["cyc/op", "kcyc/op", "Mcyc/op", "Gcyc/op", "Tcyc/op"][power]
+            }
+            Throughput::Elements(0) => self.cycles_label(),
+            Throughput::Elements(n) => {
+                for val in values {
+                    *val /= *n as f64;
+                }
+                self.per_element_label()
+            }
+            Throughput::BytesDecimal(n) => {
+                for val in values {
+                    *val /= *n as f64;
+                }
+                self.cpb_label(true)
+            }
+        }
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        self.check_discards();
+        "cycles"
+    }
+}
@@ -0,0 +1,707 @@
+//! The [`CyclesPerByte`] measurement and its options.
+
+#[cfg(feature = "criterion")]
+mod formatter;
+mod options;
+mod region;
+mod results;
+mod state;
+
+#[cfg(feature = "criterion")]
+use self::formatter::CyclesPerByteFormatter;
+#[cfg(target_os = "linux")]
+use self::state::TscDrift;
+use self::state::{Budget, Discards, Migration, Recording, Setup, Stream, Tracked};
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use self::state::{InterruptCheck, PmuEvent, Throttling};
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use crate::msr;
+#[cfg(all(feature = "rdpmc", target_os = "linux"))]
+use crate::rdpmc;
+use crate::{rdtsc, rdtsc_serialized, rdtscp_serialized, stats};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// `CyclesPerByte` measures clock cycles using the x86 or x86_64 `rdtsc` instruction. `cpb` is
+/// the preferred measurement for cryptographic algorithms.
+///
+/// Clones share their recorded samples, so a clone kept aside can be queried after the original
+/// has been handed to criterion.
+///
+/// # Bookkeeping
+///
+/// Recording, statistics, streaming and the other per-region options do their work after the end
+/// of a region has been read, so it never inflates the measured delta, but it runs before the
+/// next region can start and slow bookkeeping stretches the benchmark.
+#[derive(Clone)]
+pub struct CyclesPerByte {
+    counter: Source,
+    samples: Option<Arc<Mutex<Recording>>>,
+    stats: Option<Arc<Mutex<stats::Streaming>>>,
+    frequency: FrequencySource,
+    subtraction: SubtractionMode,
+    /// The frequency resolved from `frequency`, once it has been asked for.
+    resolved_frequency: Arc<OnceLock<Option<u64>>>,
+    migration: Option<Arc<Migration>>,
+    discards: Option<Arc<Discards>>,
+    budget: Option<Arc<Budget>>,
+    setup: Arc<Setup>,
+    /// The cycles of each pair of consecutive marks, in the order first seen.
+    phases: Arc<Mutex<Vec<(String, String, u64)>>>,
+    /// The median of the samples accepted by `measure_clean`.
+    clean_median: Arc<Mutex<stats::RunningMedian>>,
+    tracked: Option<Arc<Tracked>>,
+    /// The commit set with `with_provenance`, or `None` to take it from the environment.
+    provenance: Option<String>,
+    /// The machine set with `with_machine_id`, or `None` to take it from the environment.
+    machine: Option<String>,
+    /// The `(start, end)` cycles subtracted from every delta for the counter reads.
+    overhead: (u64, u64),
+    /// The options that failed to set up, for [`CyclesPerByte::validate`].
+    setup_errors: Vec<ConfigError>,
+    stream: Option<Arc<Mutex<Stream>>>,
+    /// The callback set with `on_sample`.
+    on_sample: Option<Arc<dyn Fn(u64) + Send + Sync>>,
+    #[cfg(feature = "metrics")]
+    histogram: Option<metrics::SharedString>,
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    interrupts: Option<Arc<InterruptCheck>>,
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pmu: Option<Arc<PmuEvent>>,
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    throttling: Option<Arc<Throttling>>,
+    #[cfg(target_os = "linux")]
+    tsc_drift: Option<Arc<TscDrift>>,
+    #[cfg(feature = "criterion")]
+    formatter: CyclesPerByteFormatter,
+}
+
+/// Environment variable that turns on [`CyclesPerByte::compact`] when set to anything but `0`.
+#[cfg(feature = "criterion")]
+const COMPACT_VAR: &str = "CPB_COMPACT";
+
+/// Environment variable that streams every delta to stderr, see [`CyclesPerByte::stream_to`],
+/// when set to anything but `0`.
+const STREAM_VAR: &str = "CPB_STREAM";
+
+/// Environment variables holding the commit to export if none was set with
+/// [`CyclesPerByte::with_provenance`], in the order they are tried.
+const COMMIT_VARS: [&str; 2] = ["GIT_COMMIT", "VERGEN_GIT_SHA"];
+
+/// Environment variable holding the machine to export if none was set with
+/// [`CyclesPerByte::with_machine_id`].
+const MACHINE_VAR: &str = "CPB_MACHINE_ID";
+
+/// Empty samples measured by [`CyclesPerByte::harness_floor`].
+const HARNESS_FLOOR_RUNS: usize = 10_000;
+/// Samples of which more were discarded than [`CyclesPerByte::max_discard_fraction`] allows, see
+/// [`CyclesPerByte::check_discards`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Discarded {
+    /// The number of discarded samples.
+    pub discarded: u64,
+    /// The number of samples.
+    pub samples: u64,
+}
+
+impl Discarded {
+    /// Returns the fraction of the samples that were discarded.
+    pub fn fraction(&self) -> f64 {
+        self.discarded as f64 / self.samples as f64
+    }
+}
+
+/// The hardware counter a [`CyclesPerByte`] reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    /// Reference cycles from the time-stamp counter (`rdtsc`), without any fences. This is the
+    /// default and the cheapest read.
+    Rdtsc,
+    /// Reference cycles from the time-stamp counter, fenced so that the measured region cannot
+    /// overlap the reads: `lfence; rdtsc; lfence` at the start and `rdtscp; lfence` at the end.
+    ///
+    /// This is the accurate choice for short regions, at the cost of a few dozen extra cycles per
+    /// measurement.
+    Serialized,
+    /// Unhalted core cycles from `IA32_FIXED_CTR1`, read through `/dev/cpu/N/msr`.
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    FixedCounter,
+    /// Unhalted core cycles from fixed counter 1, read with `rdpmc` in user mode. Like
+    /// [`Backend::Rdtsc`], the reads are not fenced.
+    #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+    Rdpmc,
+    /// A counter supplied with [`CyclesPerByte::with_counter`].
+    Custom,
+}
+
+/// Where a [`CyclesPerByte`] takes the frequency of the time-stamp counter from, to convert cycles
+/// to time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FrequencySource {
+    /// The first of [`FrequencySource::Cpuid`], [`FrequencySource::Hypervisor`],
+    /// [`FrequencySource::Sysfs`] and [`FrequencySource::Calibrated`] that gives a value, in that
+    /// order: from the exact architectural value down to the one that always works but costs
+    /// 100ms and is only as precise as the system clock. This is the default.
+    #[default]
+    Auto,
+    /// CPUID leaves `0x15` and `0x16`, see [`detect::cpuid_tsc_frequency`].
+    ///
+    /// [`detect::cpuid_tsc_frequency`]: crate::detect::cpuid_tsc_frequency
+    Cpuid,
+    /// The hypervisor's timing leaf, see [`detect::hypervisor_tsc_frequency`].
+    ///
+    /// [`detect::hypervisor_tsc_frequency`]: crate::detect::hypervisor_tsc_frequency
+    Hypervisor,
+    /// Linux's cpufreq base frequency, see [`detect::sysfs_base_frequency`].
+    ///
+    /// [`detect::sysfs_base_frequency`]: crate::detect::sysfs_base_frequency
+    Sysfs,
+    /// 100ms of busy-waiting against the system clock, see [`detect::measure_frequency`].
+    ///
+    /// [`detect::measure_frequency`]: crate::detect::measure_frequency
+    Calibrated,
+    /// A frequency in Hz known from elsewhere, e.g. the data sheet or a previous calibration.
+    Fixed(u64),
+}
+
+/// What a [`CyclesPerByte`] does when the counter reads less at the end of a region than at its
+/// start, see [`CyclesPerByte::subtraction_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SubtractionMode {
+    /// The delta is 0, which keeps a backward step from turning into a huge sample. This is the
+    /// default, for production runs.
+    #[default]
+    Saturating,
+    /// The measurement panics, for debugging a setup where the counter should never go back.
+    Panic,
+    /// The delta wraps around at 2^64, which is right if the counter really overflowed, e.g. a
+    /// custom counter narrower than the TSC scaled up to `u64`, and gives a delta close to 2^64
+    /// if it went back instead.
+    Wrapping,
+}
+
+impl SubtractionMode {
+    /// Returns the cycles from `start` to `end` in this mode.
+    ///
+    /// # Panics
+    ///
+    /// In [`SubtractionMode::Panic`], if `end` is less than `start`.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::SubtractionMode;
+    ///
+    /// for mode in [
+    ///     SubtractionMode::Saturating,
+    ///     SubtractionMode::Panic,
+    ///     SubtractionMode::Wrapping,
+    /// ] {
+    ///     assert_eq!(mode.delta(100, 350), 250);
+    ///     assert_eq!(mode.delta(100, 100), 0);
+    /// }
+    ///
+    /// assert_eq!(SubtractionMode::Saturating.delta(350, 100), 0);
+    /// assert_eq!(SubtractionMode::Wrapping.delta(350, 100), u64::MAX - 249);
+    /// assert_eq!(SubtractionMode::Wrapping.delta(u64::MAX - 9, 5), 15);
+    /// assert!(std::panic::catch_unwind(|| SubtractionMode::Panic.delta(350, 100)).is_err());
+    /// ```
+    pub fn delta(self, start: u64, end: u64) -> u64 {
+        match self {
+            SubtractionMode::Saturating => end.saturating_sub(start),
+            SubtractionMode::Panic => end.checked_sub(start).unwrap_or_else(|| {
+                panic!(
+                    "criterion-cycles-per-byte: the counter went back from {} to {}",
+                    start, end
+                )
+            }),
+            SubtractionMode::Wrapping => end.wrapping_sub(start),
+        }
+    }
+}
+
+/// What [`CyclesPerByte::validate`] found on this host.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    /// The counter that is read, see [`CyclesPerByte::active_backend`].
+    pub backend: Backend,
+    /// The smallest non-zero difference between two reads of that counter.
+    pub resolution: u64,
+    /// The counter frequency in Hz, see [`CyclesPerByte::resolved_frequency_hz`].
+    pub frequency_hz: Option<u64>,
+    /// Whether the TSC is invariant, see [`detect::invariant_tsc`].
+    ///
+    /// [`detect::invariant_tsc`]: crate::detect::invariant_tsc
+    pub invariant_tsc: bool,
+    /// The CPU the calling thread runs on, if the platform tells.
+    pub cpu: Option<usize>,
+}
+
+/// A configuration of [`CyclesPerByte`] that cannot work as intended on this host, from
+/// [`CyclesPerByte::validate`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// A backend, pinning or MSR-based option failed to set up and was left out or fell back, as
+    /// warned about on stderr when it was selected.
+    Unavailable {
+        /// What was selected, e.g. `"pinning"`.
+        feature: &'static str,
+        /// Why it failed.
+        reason: String,
+    },
+    /// The counter did not advance between two reads over a short busy loop.
+    CounterStuck(Backend),
+    /// The selected frequency source gives no frequency on this host.
+    FrequencyUnavailable(FrequencySource),
+    /// A threshold outside of the values it can take.
+    InvalidThreshold {
+        /// The option the threshold was given to.
+        option: &'static str,
+        /// The threshold.
+        value: f64,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Unavailable { feature, reason } => {
+                write!(f, "{} is unavailable ({})", feature, reason)
+            }
+            ConfigError::CounterStuck(backend) => {
+                write!(f, "the {:?} counter does not advance", backend)
+            }
+            ConfigError::FrequencyUnavailable(source) => {
+                write!(f, "the frequency source {:?} gives no frequency", source)
+            }
+            ConfigError::InvalidThreshold { option, value } => {
+                write!(f, "{} is not a valid threshold for {}", value, option)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone)]
+pub(crate) enum Source {
+    Rdtsc,
+    Serialized,
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    FixedCounter(Arc<msr::FixedCounter>),
+    #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+    Rdpmc,
+    Custom(Arc<dyn Fn() -> u64 + Send + Sync>),
+}
+
+impl Source {
+    #[inline]
+    pub(crate) fn read(&self) -> u64 {
+        match *self {
+            Source::Rdtsc => rdtsc(),
+            Source::Serialized => rdtsc_serialized(),
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            Source::FixedCounter(ref counter) => counter.read(),
+            #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+            Source::Rdpmc => rdpmc::read(),
+            Source::Custom(ref counter) => counter(),
+        }
+    }
+
+    /// Reads the counter at the end of a region, which only differs from [`Source::read`] for
+    /// [`Backend::Serialized`].
+    #[inline]
+    pub(crate) fn read_end(&self) -> u64 {
+        match *self {
+            Source::Serialized => rdtscp_serialized(),
+            _ => self.read(),
+        }
+    }
+}
+
+impl Default for CyclesPerByte {
+    fn default() -> Self {
+        CyclesPerByte::new()
+    }
+}
+
+/// Returns a measurement whose counter reads `readings` in order, a start and an end per region.
+#[cfg(test)]
+pub(crate) fn scripted(readings: &[u64]) -> CyclesPerByte {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let readings = readings.to_vec();
+    let next = AtomicUsize::new(0);
+    CyclesPerByte::new().with_counter(move || readings[next.fetch_add(1, Ordering::Relaxed)])
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "criterion")]
+    use super::formatter::counter_unit;
+    use super::*;
+    #[cfg(feature = "criterion")]
+    use criterion::{measurement::Measurement, Throughput};
+    use std::io::{self, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[cfg(feature = "criterion")]
+    #[test]
+    fn counter_unit_names_what_the_counter_counts() {
+        assert_eq!(counter_unit("x86_64", false), "cycles");
+        assert_eq!(counter_unit("x86_64", true), "cycles");
+        assert_eq!(counter_unit("arm", false), "cycles");
+        assert_eq!(counter_unit("aarch64", false), "ticks");
+        assert_eq!(counter_unit("aarch64", true), "cycles (est.)");
+    }
+
+    #[test]
+    fn measure_returns_and_records_the_delta() {
+        let measurement = scripted(&[1000, 1250]).record_samples();
+        assert_eq!(measurement.measure(|| (0..64u32).sum::<u32>()), 250);
+        assert_eq!(measurement.samples(), [250]);
+    }
+
+    #[test]
+    fn measure_warm_cold_measures_two_calls() {
+        let measurement = scripted(&[0, 900, 1_000, 1_150]).record_samples();
+        assert_eq!(measurement.measure_warm_cold(|| ()), (900, 150));
+        assert_eq!(measurement.samples(), [900, 150]);
+    }
+
+    #[test]
+    fn subtraction_mode_decides_a_backward_step() {
+        let backwards = |mode| scripted(&[500, 200]).subtraction_mode(mode);
+        assert_eq!(backwards(SubtractionMode::Saturating).measure(|| ()), 0);
+        assert_eq!(
+            backwards(SubtractionMode::Wrapping).measure(|| ()),
+            u64::MAX - 299
+        );
+        let strict = backwards(SubtractionMode::Panic);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| strict.measure(|| ()))).is_err());
+    }
+
+    #[test]
+    fn check_discards_reports_and_restarts() {
+        // The second and third regions end before they start.
+        let measurement =
+            scripted(&[100, 150, 200, 190, 300, 250, 400, 420]).max_discard_fraction(0.25);
+        for _ in 0..3 {
+            measurement.measure(|| ());
+        }
+        assert_eq!(measurement.discarded_samples(), 2);
+        assert_eq!(
+            measurement.check_discards(),
+            Err(Discarded {
+                discarded: 2,
+                samples: 3
+            })
+        );
+        measurement.measure(|| ());
+        assert_eq!(measurement.check_discards(), Ok(()));
+    }
+
+    #[cfg(feature = "criterion")]
+    #[test]
+    fn formatter_keeps_the_first_benchmark_over_the_discard_limit() {
+        let measurement =
+            scripted(&[100, 150, 200, 190, 300, 250, 400, 420]).max_discard_fraction(0.25);
+        let formatter = measurement.formatter();
+        for _ in 0..3 {
+            measurement.measure(|| ());
+        }
+        assert_eq!(formatter.format_value(50.0), "50.0000 cycles");
+        measurement.measure(|| ());
+        assert_eq!(formatter.format_value(20.0), "20.0000 cycles");
+        assert_eq!(
+            measurement.check_discards(),
+            Err(Discarded {
+                discarded: 2,
+                samples: 3
+            })
+        );
+        assert_eq!(measurement.check_discards(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "max_discard_fraction must be between 0 and 1")]
+    fn max_discard_fraction_rejects_nan() {
+        let _ = CyclesPerByte::new().max_discard_fraction(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 deltas recorded under \"b\", but the t-test needs at least two")]
+    fn assert_indistinguishable_needs_two_deltas_per_id() {
+        let measurement = scripted(&[0, 10, 0, 12, 0, 11]).record_samples();
+        crate::metadata::set_id("a");
+        measurement.measure(|| ());
+        measurement.measure(|| ());
+        crate::metadata::set_id("b");
+        measurement.measure(|| ());
+        crate::metadata::clear();
+        let _ = measurement.assert_indistinguishable("a", "b", 0.05);
+    }
+
+    #[test]
+    fn spent_budget_saturates() {
+        let measurement = scripted(&[0, u64::MAX, 0, u64::MAX]).cycle_budget(u64::MAX - 1);
+        measurement.measure(|| ());
+        measurement.measure(|| ());
+        assert!(measurement.budget_exceeded());
+    }
+
+    #[test]
+    fn compensate_overhead_splits_by_the_cost_of_each_read() {
+        // Back-to-back start reads cost 20, end reads 40 and an empty region 90, in the order
+        // they are calibrated, after which a region reads 1090.
+        let runs = HARNESS_FLOOR_RUNS as u64;
+        let calls = AtomicU64::new(0);
+        let value = AtomicU64::new(0);
+        let measurement = CyclesPerByte::new()
+            .with_counter(move || {
+                let call = calls.fetch_add(1, Ordering::Relaxed);
+                let step = match call / (2 * runs) {
+                    0 => 20,
+                    1 => 40,
+                    2 => 90 * (call % 2),
+                    _ => 1090 * (call % 2),
+                };
+                value.fetch_add(step, Ordering::Relaxed) + step
+            })
+            .compensate_overhead();
+        assert_eq!(
+            (measurement.start_overhead(), measurement.end_overhead()),
+            (30, 60)
+        );
+        assert_eq!(measurement.measure(|| ()), 1000);
+    }
+
+    #[test]
+    fn compensate_overhead_corrects_each_sub_region() {
+        let mut measurement = scripted(&[0, 100, 150, 200, 230, 300, 1000, 1300]);
+        measurement.overhead = (5, 7);
+        let marked = measurement.measure(|| {
+            crate::tic();
+            crate::toc();
+            crate::tic();
+            crate::toc();
+        });
+        assert_eq!(marked, 80 - 2 * 12);
+        assert_eq!(measurement.measure(|| ()), 300 - 12);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tsc_drift_compares_the_first_and_last_rates() {
+        let measurement = CyclesPerByte::new().detect_tsc_drift(0.01);
+        let drift = measurement.tsc_drift.as_ref().unwrap();
+        // 3 ticks per nanosecond, slowed to 2 after the 16th of 25 regions a microsecond apart.
+        let mut ticks = 0;
+        for region in 0..25 {
+            ticks += if region > 16 { 2_000 } else { 3_000 };
+            drift.sample_at(ticks, || region * 1_000);
+        }
+        assert_eq!(measurement.tsc_time_drift(), Some(2.0 / 3.0));
+        assert_eq!(CyclesPerByte::new().tsc_time_drift(), None);
+    }
+
+    #[test]
+    fn timeline_pairs_each_start_with_its_delta() {
+        let measurement = scripted(&[100, 130, 500, 520]).record_samples();
+        measurement.measure(|| ());
+        measurement.measure(|| ());
+        assert_eq!(measurement.timeline(), [(100, 30), (500, 20)]);
+        assert_eq!(measurement.samples(), [30, 20]);
+    }
+
+    #[test]
+    fn on_sample_sees_every_delta() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let measurement = scripted(&[100, 220, 500, 590])
+            .on_sample(Box::new(move |delta| sink.lock().unwrap().push(delta)));
+        assert_eq!(measurement.measure(|| ()), 120);
+        assert_eq!(measurement.measure(|| ()), 90);
+        assert_eq!(*seen.lock().unwrap(), [120, 90]);
+    }
+
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    #[test]
+    fn pmu_reads_stay_outside_the_interrupt_check() {
+        // Every msr read runs 100 ring 0 instructions, half of them before it samples.
+        let kernel = Arc::new(AtomicU64::new(0));
+        let kernel_instructions = {
+            let kernel = kernel.clone();
+            move || kernel.fetch_add(100, Ordering::Relaxed) + 50
+        };
+        let events = AtomicU64::new(0);
+        let event_counter = move || {
+            kernel.fetch_add(100, Ordering::Relaxed);
+            events.fetch_add(4, Ordering::Relaxed)
+        };
+        let cycles = AtomicU64::new(0);
+        let measurement = CyclesPerByte::new()
+            .with_counter(move || cycles.fetch_add(10, Ordering::Relaxed))
+            .with_kernel_instructions(Box::new(kernel_instructions))
+            .with_event_counter(Box::new(event_counter));
+        for _ in 0..8 {
+            assert_eq!(measurement.measure(|| ()), 10);
+        }
+        assert_eq!(measurement.suspect_samples(), 0);
+        assert_eq!(measurement.cycles_per_event(), Some(2.5));
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_to_writes_a_line_per_region() {
+        let captured = Captured::default();
+        let measurement = scripted(&[100, 220, 500, 590]).stream_to(captured.clone());
+        measurement.measure(|| ());
+        measurement.measure(|| ());
+        drop(measurement);
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "{\"delta\":120,\"ts\":220}\n{\"delta\":90,\"ts\":590}\n"
+        );
+    }
+
+    #[test]
+    fn stream_variable_writes_a_line_per_region() {
+        for off in [None, Some(""), Some("0")] {
+            let stream = Stream::from_env(off.map(Into::into), Box::new(Captured::default()));
+            assert!(stream.is_none(), "{:?}", off);
+        }
+        let captured = Captured::default();
+        let mut measurement = scripted(&[100, 220, 500, 590]);
+        measurement.stream = Stream::from_env(Some("1".into()), Box::new(captured.clone()));
+        measurement.measure(|| ());
+        measurement.measure(|| ());
+        drop(measurement);
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "{\"delta\":120,\"ts\":220}\n{\"delta\":90,\"ts\":590}\n"
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn with_metrics_records_every_delta() {
+        use metrics::{Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Recorder};
+        use metrics::{SharedString, Unit};
+
+        #[derive(Default)]
+        struct Values(Mutex<Vec<f64>>);
+
+        impl HistogramFn for Values {
+            fn record(&self, value: f64) {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+
+        struct MockRecorder(Arc<Values>);
+
+        impl Recorder for MockRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn register_counter(&self, _: &Key, _: &metrics::Metadata<'_>) -> Counter {
+                Counter::noop()
+            }
+            fn register_gauge(&self, _: &Key, _: &metrics::Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+            fn register_histogram(&self, key: &Key, _: &metrics::Metadata<'_>) -> Histogram {
+                assert_eq!(key.name(), "cycles.parse");
+                Histogram::from_arc(self.0.clone())
+            }
+        }
+
+        let measurement = scripted(&[0, 120, 500, 590]).with_metrics("cycles.parse");
+        let values = Arc::new(Values::default());
+        metrics::with_local_recorder(&MockRecorder(values.clone()), || {
+            measurement.measure(|| ());
+            measurement.measure(|| ());
+        });
+        assert_eq!(*values.0.lock().unwrap(), [120.0, 90.0]);
+    }
+
+    #[cfg(feature = "criterion")]
+    #[test]
+    fn coefficient_of_variation_annotates_until_cleared() {
+        let measurement = scripted(&[0, 90, 0, 110, 0, 90, 0, 110]).coefficient_of_variation(true);
+        let formatter = measurement.formatter();
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Bytes(10), 100.0),
+            "10.0000 cpb"
+        );
+        for _ in 0..4 {
+            measurement.measure(|| ());
+        }
+        let stats = measurement.stats();
+        assert_eq!(stats.mean, 100.0);
+        assert!((stats.std_dev - 11.547).abs() < 0.001);
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Bytes(10), 100.0),
+            "10.0000 cpb (cv=11.5%)"
+        );
+        measurement.clear_samples();
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Bytes(10), 100.0),
+            "10.0000 cpb"
+        );
+    }
+
+    #[cfg(feature = "criterion")]
+    #[test]
+    fn combined_units_fall_back_to_cycles_without_elements() {
+        let measurement = CyclesPerByte::new().combined_units(true);
+        let formatter = measurement.formatter();
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Elements(0), 100.0),
+            "100.0000 cycles, 100.0000 cycles"
+        );
+        let mut values = [100.0];
+        let unit = formatter.scale_throughputs(100.0, &Throughput::Elements(0), &mut values);
+        assert_eq!((unit, values), ("cycles", [100.0]));
+    }
+
+    #[cfg(feature = "criterion")]
+    #[test]
+    fn p99_annotation_only_annotates_cpb() {
+        let measurement = scripted(&[0, 340]).p99_annotation(true);
+        let formatter = measurement.formatter();
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Bytes(100), 201.0),
+            "2.0100 cpb"
+        );
+        measurement.measure(|| ());
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Bytes(100), 201.0),
+            "2.0100 cpb (p99: 3.4000)"
+        );
+        assert_eq!(
+            formatter.format_throughput(&Throughput::Elements(4), 201.0),
+            "201.0000 cycles/4"
+        );
+    }
+}
@@ -0,0 +1,1427 @@
+//! The options of [`CyclesPerByte`], set up by its builder methods.
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use super::state::{InterruptCheck, PmuEvent, Throttling, ThrottlingState};
+#[cfg(target_os = "linux")]
+use super::state::{TscDrift, TscDriftState};
+#[cfg(feature = "criterion")]
+use super::{formatter::CyclesPerByteFormatter, COMPACT_VAR};
+use super::{
+    state::{Budget, Discards, Migration, Recording, Setup, Stream, Tracked},
+    Backend, ConfigError, CyclesPerByte, FrequencySource, Source, SubtractionMode,
+    ValidationReport, HARNESS_FLOOR_RUNS, STREAM_VAR,
+};
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use crate::msr;
+use crate::{affinity, detect, stats};
+use std::{
+    hint::black_box,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+impl CyclesPerByte {
+    /// Creates a measurement using `rdtsc`.
+    pub fn new() -> Self {
+        let setup = Arc::new(Setup::default());
+        CyclesPerByte {
+            counter: Source::Rdtsc,
+            samples: None,
+            stats: None,
+            frequency: FrequencySource::Auto,
+            subtraction: SubtractionMode::Saturating,
+            resolved_frequency: Arc::new(OnceLock::new()),
+            migration: None,
+            discards: None,
+            budget: None,
+            setup: setup.clone(),
+            phases: Arc::new(Mutex::new(Vec::new())),
+            clean_median: Arc::new(Mutex::new(stats::RunningMedian::new())),
+            tracked: None,
+            provenance: None,
+            machine: None,
+            overhead: (0, 0),
+            setup_errors: Vec::new(),
+            stream: Stream::from_env(std::env::var_os(STREAM_VAR), Box::new(io::stderr())),
+            on_sample: None,
+            #[cfg(feature = "metrics")]
+            histogram: None,
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            interrupts: None,
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            pmu: None,
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            throttling: None,
+            #[cfg(target_os = "linux")]
+            tsc_drift: None,
+            #[cfg(feature = "criterion")]
+            formatter: CyclesPerByteFormatter {
+                decimal_suffix: true,
+                relative_marker: false,
+                combined_units: false,
+                thousands_separator: None,
+                variation: None,
+                p99: None,
+                discards: None,
+                setup,
+                tracked: None,
+                block_size: 0,
+                per_operation: false,
+                theoretical_cpb: None,
+                plausible_cpb: None,
+                recording: None,
+                #[cfg(all(feature = "msr", target_os = "linux"))]
+                pmu: None,
+                bytes_from_id: false,
+                frequency_configured: false,
+                compact: std::env::var_os(COMPACT_VAR).is_some_and(|v| !v.is_empty() && v != "0"),
+            },
+        }
+    }
+
+    /// Creates a measurement for comparisons within a single run, using the cheapest read.
+    ///
+    /// This is [`Backend::Rdtsc`] without any fences, so the counter reads may overlap the
+    /// beginning and end of the measured region and their cost is included in every sample. That
+    /// skews absolute numbers, especially for tiny regions, but it skews all implementations
+    /// measured on the same machine in the same run alike, so their ratios stay meaningful.
+    /// Results are only valid for such same-run comparisons; use [`Backend::Serialized`] for
+    /// numbers meant to be compared against other runs or published.
+    ///
+    /// Units are marked with an asterisk (`cpb*`, `cycles*`) as a reminder, which can be turned
+    /// off with `CyclesPerByte::relative_marker`.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{Backend, CyclesPerByte};
+    ///
+    /// let measurement = CyclesPerByte::relative_mode();
+    /// assert_eq!(measurement.active_backend(), Backend::Rdtsc);
+    ///
+    /// # #[cfg(feature = "criterion")]
+    /// # {
+    /// use criterion::{measurement::Measurement, Throughput};
+    ///
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(formatter.format_value(128.0), "128.0000 cycles*");
+    /// assert_eq!(formatter.format_throughput(&Throughput::Bytes(64), 128.0), "2.0000 cpb*");
+    /// # }
+    /// ```
+    pub fn relative_mode() -> Self {
+        let measurement = CyclesPerByte::new().backend(Backend::Rdtsc);
+        #[cfg(feature = "criterion")]
+        let measurement = measurement.relative_marker(true);
+        measurement
+    }
+
+    /// Selects the counter to read, falling back to `rdtsc` if it is unavailable.
+    ///
+    /// [`Backend::Custom`] keeps the current counter; use [`CyclesPerByte::with_counter`] instead.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.counter = match backend {
+            Backend::Rdtsc => Source::Rdtsc,
+            Backend::Serialized if backend.is_available() => Source::Serialized,
+            Backend::Serialized => {
+                return self.fall_back("Backend::Serialized", "rdtscp is not supported".to_owned())
+            }
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            Backend::FixedCounter => match msr::FixedCounter::open() {
+                Ok(counter) => Source::FixedCounter(Arc::new(counter)),
+                Err(e) => {
+                    return self.fall_back(
+                        "Backend::FixedCounter",
+                        format!("cannot use IA32_FIXED_CTR1: {}", e),
+                    )
+                }
+            },
+            #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+            Backend::Rdpmc if backend.is_available() => Source::Rdpmc,
+            #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+            Backend::Rdpmc => {
+                return self.fall_back(
+                    "Backend::Rdpmc",
+                    "user-mode rdpmc of fixed counter 1 is not enabled".to_owned(),
+                )
+            }
+            Backend::Custom => return self,
+        };
+        self
+    }
+
+    /// Falls back to `rdtsc` because the backend `feature` is unavailable for `reason`.
+    fn fall_back(mut self, feature: &'static str, reason: String) -> Self {
+        eprintln!(
+            "criterion-cycles-per-byte: {}, falling back to rdtsc",
+            reason
+        );
+        self.counter = Source::Rdtsc;
+        self.setup_errors
+            .push(ConfigError::Unavailable { feature, reason });
+        self
+    }
+
+    /// Replaces the hardware counter with `counter`, e.g. to script the readings in tests.
+    pub fn with_counter<F>(mut self, counter: F) -> Self
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.counter = Source::Custom(Arc::new(counter));
+        self
+    }
+
+    /// Selects what happens when the counter reads less at the end of a region than at its start,
+    /// by default [`SubtractionMode::Saturating`].
+    ///
+    /// This only decides how `end - start` is computed: overhead compensation and [`tic`](crate::tic)
+    /// sub-regions still saturate, and a backward step still counts as discarded for
+    /// [`CyclesPerByte::max_discard_fraction`].
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{CyclesPerByte, SubtractionMode};
+    ///
+    /// // Fail loudly rather than record a 0 if the counter ever steps back.
+    /// let measurement = CyclesPerByte::new().subtraction_mode(SubtractionMode::Panic);
+    /// ```
+    pub fn subtraction_mode(mut self, mode: SubtractionMode) -> Self {
+        self.subtraction = mode;
+        self
+    }
+
+    /// Reads the counter right away as `n` empty regions would, a start and an end read each, and
+    /// throws the values away, so that the reads are cached and their branches predicted before
+    /// the first real sample. Call it last, once the counter is selected.
+    ///
+    /// This only warms the measurement's own reads, in microseconds, and does not replace
+    /// criterion's `warm_up_time`; it mostly matters for [`CyclesPerByte::measure`].
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{
+    ///     atomic::{AtomicU64, Ordering},
+    ///     Arc,
+    /// };
+    ///
+    /// let reads = Arc::new(AtomicU64::new(0));
+    /// let counter = reads.clone();
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || counter.fetch_add(1, Ordering::Relaxed))
+    ///     .prewarm_reads(8);
+    /// assert_eq!(reads.load(Ordering::Relaxed), 16);
+    /// assert!(measurement.samples().is_empty());
+    /// ```
+    pub fn prewarm_reads(self, n: u32) -> Self {
+        for _ in 0..n {
+            black_box(self.read());
+            black_box(self.read_end());
+        }
+        self
+    }
+
+    /// Pins the calling thread to the CPU it is currently running on, see
+    /// [`CyclesPerByte::pinned_to`]. Prints a warning if that CPU cannot be determined.
+    pub fn pinned(mut self) -> Self {
+        match affinity::current_cpu() {
+            Ok(cpu) => self.pinned_to(cpu),
+            Err(e) => {
+                if cfg!(any(target_os = "linux", windows)) {
+                    eprintln!(
+                        "criterion-cycles-per-byte: cannot determine the current CPU ({})",
+                        e
+                    );
+                    self.setup_errors.push(ConfigError::Unavailable {
+                        feature: "pinning",
+                        reason: format!("cannot determine the current CPU: {}", e),
+                    });
+                }
+                self
+            }
+        }
+    }
+
+    /// Pins the calling thread to `cpu`, so that all measurements read the same core's counter
+    /// and are not disturbed by migrations. Prints a warning if that fails.
+    ///
+    /// criterion runs the benchmarks on the thread that calls it, so construct the measurement
+    /// on that thread (as `criterion_group!` does). Pin before selecting a backend that is tied
+    /// to a CPU, such as `Backend::FixedCounter`.
+    ///
+    /// - On Linux this uses `sched_setaffinity`.
+    /// - On Windows this uses `SetThreadAffinityMask`, which can only address the 64 logical
+    ///   processors of the thread's processor group, so `cpu` is relative to that group.
+    /// - Elsewhere this does nothing.
+    pub fn pinned_to(mut self, cpu: usize) -> Self {
+        if cfg!(any(target_os = "linux", windows)) {
+            if let Err(e) = affinity::pin_current_thread(cpu) {
+                eprintln!(
+                    "criterion-cycles-per-byte: cannot pin to CPU {} ({})",
+                    cpu, e
+                );
+                self.setup_errors.push(ConfigError::Unavailable {
+                    feature: "pinning",
+                    reason: format!("cannot pin to CPU {}: {}", cpu, e),
+                });
+            }
+        }
+        self
+    }
+
+    /// Prints a warning if [`detect::smt_sibling_busy`] finds the calling thread's SMT sibling
+    /// busy, which explains otherwise puzzling noise in the results. This blocks for about 50ms
+    /// and does nothing on platforms other than Linux.
+    pub fn warn_on_busy_sibling(self) -> Self {
+        if detect::smt_sibling_busy() == Some(true) {
+            eprintln!(
+                "criterion-cycles-per-byte: another hardware thread on this core is busy, cycle counts will be inflated"
+            );
+        }
+        self
+    }
+
+    /// Tracks whether the measured regions all ran on the same core, see
+    /// [`CyclesPerByte::migrated`].
+    ///
+    /// Each region reads `TSC_AUX` with `rdtscp` just before it starts and just after it ends,
+    /// outside of the measured region. Operating systems load a per-core value into `TSC_AUX`
+    /// (Linux uses the CPU number and NUMA node), so a change means the thread moved. This is a
+    /// single per-run signal rather than a per-sample one, unless combined with
+    /// [`CyclesPerByte::max_discard_fraction`], and costs two `rdtscp` per region.
+    ///
+    /// It requires `rdtscp` and is only implemented for x86_64; elsewhere, or if the CPU lacks
+    /// `rdtscp`, this does nothing and [`CyclesPerByte::migrated`] always returns `false`.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().detect_migration();
+    /// // ... run the benchmarks with a clone of `measurement` ...
+    /// if measurement.migrated() {
+    ///     eprintln!("the benchmark moved between cores, pin it with taskset");
+    /// }
+    /// ```
+    pub fn detect_migration(mut self) -> Self {
+        if cfg!(target_arch = "x86_64") && detect::has_rdtscp() {
+            self.migration = Some(Arc::new(Migration {
+                first: AtomicU64::new(Migration::UNSET),
+                migrated: AtomicBool::new(false),
+                region: AtomicU64::new(Migration::UNSET),
+            }));
+        }
+        self
+    }
+
+    /// Counts the regions during which the kernel ran, which are likely inflated by an interrupt,
+    /// see [`CyclesPerByte::suspect_samples`].
+    ///
+    /// This counts ring 0 instructions in `IA32_FIXED_CTR0` around each region, and flags a region
+    /// that sees more than twice as many as an empty one, so regions that allocate or do I/O are
+    /// always flagged. It needs the setup of `Backend::FixedCounter`, described in the crate
+    /// documentation, works best with [`Backend::Serialized`], and warns if the counter cannot be
+    /// set up.
+    ///
+    /// ```rust,no_run
+    /// use criterion_cycles_per_byte::{Backend, CyclesPerByte};
+    ///
+    /// let measurement = CyclesPerByte::new()
+    ///     .pinned()
+    ///     .backend(Backend::Serialized)
+    ///     .detect_interrupts();
+    /// // ... run the benchmarks with a clone of `measurement` ...
+    /// eprintln!("{} interrupted samples", measurement.suspect_samples());
+    /// ```
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub fn detect_interrupts(mut self) -> Self {
+        let counter = match msr::KernelInstructions::open() {
+            Ok(counter) => counter,
+            Err(e) => {
+                eprintln!(
+                    "criterion-cycles-per-byte: cannot use IA32_FIXED_CTR0 ({}), not detecting interrupts",
+                    e
+                );
+                self.setup_errors.push(ConfigError::Unavailable {
+                    feature: "interrupt detection",
+                    reason: format!("cannot use IA32_FIXED_CTR0: {}", e),
+                });
+                return self;
+            }
+        };
+        self.with_kernel_instructions(Box::new(move || counter.read()))
+    }
+
+    /// Flags the regions around which `counter`, of ring 0 instructions, advances by more than
+    /// twice as much as around an empty region.
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub(super) fn with_kernel_instructions(
+        mut self,
+        counter: Box<dyn Fn() -> u64 + Send + Sync>,
+    ) -> Self {
+        let mut baseline = u64::MAX;
+        for _ in 0..64 {
+            let before = counter();
+            self.read();
+            self.read_end();
+            baseline = baseline.min(counter().saturating_sub(before));
+        }
+
+        self.interrupts = Some(Arc::new(InterruptCheck {
+            counter,
+            threshold: baseline.saturating_mul(2),
+            start: AtomicU64::new(0),
+            suspect: AtomicU64::new(0),
+        }));
+        self
+    }
+
+    /// Counts the hardware event `event` with unit mask `umask` in a general-purpose performance
+    /// counter around each region, and reports the cycles per event alongside the results, e.g.
+    /// `2.0000 cpb (4.00 cycles/event)`, see [`CyclesPerByte::cycles_per_event`].
+    ///
+    /// The two bytes are the event select and unit mask of Intel's `IA32_PERFEVTSELx` encoding, as
+    /// listed in the Intel SDM, volume 3B; a wrong encoding counts something else, with no error.
+    /// Only ring 3 is counted. This programs `IA32_PMC0` through the msr driver, so it needs the
+    /// setup of `Backend::FixedCounter` described in the crate documentation, and warns if the
+    /// counter cannot be set up. The ratio covers the benchmark being reported, as
+    /// [`CyclesAndWallTime`] does with the frequency.
+    ///
+    /// [`CyclesAndWallTime`]: crate::CyclesAndWallTime
+    ///
+    /// ```rust,no_run
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// // FP_ARITH_INST_RETIRED.512B_PACKED_SINGLE
+    /// let measurement = CyclesPerByte::new().pinned().with_pmu_event(0xc7, 0x80);
+    /// measurement.measure(|| ());
+    /// println!("{:?} cycles per AVX-512 instruction", measurement.cycles_per_event());
+    /// ```
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub fn with_pmu_event(mut self, event: u8, umask: u8) -> Self {
+        let counter = match msr::ProgrammedCounter::open(event, umask) {
+            Ok(counter) => counter,
+            Err(e) => {
+                eprintln!(
+                    "criterion-cycles-per-byte: cannot program IA32_PMC0 ({}), not counting event {:#04x}/{:#04x}",
+                    e, event, umask
+                );
+                self.setup_errors.push(ConfigError::Unavailable {
+                    feature: "PMU event",
+                    reason: format!("cannot program IA32_PMC0: {}", e),
+                });
+                return self;
+            }
+        };
+        self.with_event_counter(Box::new(move || counter.read()))
+    }
+
+    /// Reports the cycles per increment of `counter` alongside the results.
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub(super) fn with_event_counter(
+        mut self,
+        counter: Box<dyn Fn() -> u64 + Send + Sync>,
+    ) -> Self {
+        let pmu = Arc::new(PmuEvent {
+            counter,
+            start: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+            events: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+        });
+        #[cfg(feature = "criterion")]
+        {
+            self.formatter.pmu = Some(pmu.clone());
+        }
+        self.pmu = Some(pmu);
+        self
+    }
+
+    /// Warns at the end of the run if the core's effective frequency changed by more than
+    /// `threshold` (e.g. `0.02` for 2%) between its beginning and its end, see
+    /// [`CyclesPerByte::frequency_drift_ratio`].
+    ///
+    /// An invariant TSC keeps its rate when the core is throttled, so `rdtsc` results grow as the
+    /// core slows down. This compares the ratio of `IA32_APERF` to `IA32_MPERF` over the first
+    /// region with the one over the last, when the last clone is dropped. It needs read access to
+    /// the msr driver, and warns if the counters cannot be read.
+    ///
+    /// ```rust,no_run
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().pinned().detect_throttling(0.02);
+    /// // ... run the benchmarks with a clone of `measurement` ...
+    /// if let Some(drift) = measurement.frequency_drift_ratio() {
+    ///     println!("effective frequency changed by {:+.2}%", (drift - 1.0) * 100.0);
+    /// }
+    /// ```
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub fn detect_throttling(mut self, threshold: f64) -> Self {
+        let clocks = match msr::PerformanceClocks::open() {
+            Ok(clocks) => clocks,
+            Err(e) => {
+                eprintln!(
+                    "criterion-cycles-per-byte: cannot read IA32_APERF and IA32_MPERF ({}), not detecting throttling",
+                    e
+                );
+                self.setup_errors.push(ConfigError::Unavailable {
+                    feature: "throttling detection",
+                    reason: format!("cannot read IA32_APERF and IA32_MPERF: {}", e),
+                });
+                return self;
+            }
+        };
+        let previous = clocks.read();
+        self.throttling = Some(Arc::new(Throttling {
+            clocks,
+            threshold,
+            state: Mutex::new(ThrottlingState {
+                previous,
+                first: None,
+                last: None,
+            }),
+        }));
+        self
+    }
+
+    /// Warns at the end of the run if the rate of the counter relative to real time changed by
+    /// more than `tolerance` (e.g. `0.01` for 1%) between its beginning and its end, see
+    /// [`CyclesPerByte::tsc_time_drift`].
+    ///
+    /// A hypervisor may scale or offset the TSC, e.g. after live migration, so cycle counts change
+    /// for no reason visible in the guest. Every 8th region reads `CLOCK_MONOTONIC_RAW` along with
+    /// the counter, and the ticks per nanosecond of the first two sampled regions are compared with
+    /// those of the last two when the last clone is dropped. Available on Linux.
+    ///
+    /// ```rust,no_run
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::{
+    ///     sync::{
+    ///         atomic::{AtomicU64, Ordering},
+    ///         Arc, Mutex,
+    ///     },
+    ///     thread,
+    ///     time::{Duration, Instant},
+    /// };
+    ///
+    /// // A counter of 3 ticks per nanosecond, slowed to 2 halfway through.
+    /// let rate = Arc::new(AtomicU64::new(3));
+    /// let ticks = rate.clone();
+    /// let clock = Mutex::new((Instant::now(), 0u64));
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || {
+    ///         let mut clock = clock.lock().unwrap();
+    ///         let now = Instant::now();
+    ///         clock.1 += (now - clock.0).as_nanos() as u64 * ticks.load(Ordering::Relaxed);
+    ///         clock.0 = now;
+    ///         clock.1
+    ///     })
+    ///     .detect_tsc_drift(0.01);
+    ///
+    /// for i in 0..25 {
+    ///     if i == 16 {
+    ///         rate.store(2, Ordering::Relaxed);
+    ///     }
+    ///     measurement.measure(|| thread::sleep(Duration::from_millis(1)));
+    /// }
+    /// let drift = measurement.tsc_time_drift().unwrap();
+    /// assert!((drift - 2.0 / 3.0).abs() < 0.01, "{}", drift);
+    /// assert_eq!(CyclesPerByte::new().tsc_time_drift(), None);
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn detect_tsc_drift(mut self, tolerance: f64) -> Self {
+        self.tsc_drift = Some(Arc::new(TscDrift {
+            tolerance,
+            regions: AtomicU64::new(0),
+            state: Mutex::new(TscDriftState {
+                previous: None,
+                first: None,
+                last: None,
+            }),
+        }));
+        self
+    }
+
+    /// Flags the run if more than `fraction` of a benchmark's samples had to be discarded, so that
+    /// CI catches an unreliable run instead of publishing its numbers.
+    ///
+    /// A sample counts as discarded if the counter went backwards during it, which happens when
+    /// the TSCs of different cores are not synchronized and is measured as 0 cycles, or if
+    /// [`CyclesPerByte::detect_migration`] is enabled and the sample started and ended on
+    /// different cores. criterion cannot drop a sample, so these still go into its statistics.
+    ///
+    /// With criterion, the samples of each benchmark are checked when its results are first
+    /// formatted, and a warning is printed for a benchmark over the limit; the first such
+    /// benchmark is then reported by [`CyclesPerByte::check_discards`]. Without criterion, call
+    /// [`CyclesPerByte::check_discards`] after each group of samples.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` is not between 0 and 1.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().max_discard_fraction(0.01);
+    /// for _ in 0..100 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// if let Err(discarded) = measurement.check_discards() {
+    ///     eprintln!("{} of {} samples discarded", discarded.discarded, discarded.samples);
+    /// }
+    /// ```
+    pub fn max_discard_fraction(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "max_discard_fraction must be between 0 and 1, got {}",
+            fraction
+        );
+        let discards = Arc::new(Discards {
+            max_fraction: fraction,
+            samples: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+            exceeded: Mutex::new(None),
+        });
+        #[cfg(feature = "criterion")]
+        {
+            self.formatter.discards = Some(discards.clone());
+        }
+        self.discards = Some(discards);
+        self
+    }
+
+    /// Sets a budget of `cycles` for all regions measured from now on, see
+    /// [`CyclesPerByte::budget_exceeded`].
+    ///
+    /// This is meant for loops of [`CyclesPerByte::measure`] calls that should stop after a
+    /// bounded amount of measuring rather than a fixed count, e.g. over large buffers. Only the
+    /// measured deltas count, not the time between them. criterion decides on its own how many
+    /// samples to take, so with criterion the budget only reports, it does not end a benchmark;
+    /// use criterion's `measurement_time` and `sample_size` for that.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// // Every read advances the counter by 300, so every region takes 300 cycles.
+    /// let counter = AtomicU64::new(0);
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || counter.fetch_add(300, Ordering::Relaxed))
+    ///     .cycle_budget(1000);
+    ///
+    /// let mut runs = 0;
+    /// while !measurement.budget_exceeded() {
+    ///     measurement.measure(|| ());
+    ///     runs += 1;
+    /// }
+    /// assert_eq!(runs, 4);
+    /// assert!(!CyclesPerByte::new().budget_exceeded());
+    /// ```
+    pub fn cycle_budget(mut self, cycles: u64) -> Self {
+        self.budget = Some(Arc::new(Budget {
+            limit: cycles,
+            spent: AtomicU64::new(0),
+        }));
+        self
+    }
+
+    /// Records every measured delta along with the counter value it started at.
+    ///
+    /// Each entry covers one region, a batch of criterion's iterations including warm-up, and
+    /// recording allocates between regions, see [bookkeeping](CyclesPerByte#bookkeeping). Deltas
+    /// measured while an id is set with [`metadata::set_id`] are also kept under that id, see
+    /// [`CyclesPerByte::samples_with_id`].
+    ///
+    /// [`metadata::set_id`]: crate::metadata::set_id
+    pub fn record_samples(mut self) -> Self {
+        self.samples = Some(Arc::new(Mutex::new(Recording::default())));
+        #[cfg(feature = "criterion")]
+        {
+            self.formatter.recording = self.samples.clone();
+        }
+        self
+    }
+
+    /// Keeps count, minimum, maximum, mean and estimated median, 90th and 99th percentile of the
+    /// measured deltas in constant memory, for runs too long for [`CyclesPerByte::record_samples`].
+    pub fn streaming_stats(mut self) -> Self {
+        self.stats
+            .get_or_insert_with(|| Arc::new(Mutex::new(stats::Streaming::new())));
+        self
+    }
+
+    /// Records every measured delta in the histogram `name` of the [`metrics`] facade, for cycle
+    /// sampling in production code rather than benchmarks. Requires the `metrics` feature.
+    ///
+    /// Each record looks up the histogram in the installed recorder, which costs far more than
+    /// `rdtsc`, see [bookkeeping](CyclesPerByte#bookkeeping); sample a fraction of the calls in hot
+    /// paths.
+    ///
+    /// [`metrics`]: https://docs.rs/metrics/0.24
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// // Recorded in whatever recorder is installed, e.g. a Prometheus exporter.
+    /// let measurement = CyclesPerByte::new().with_metrics("cycles.parse");
+    /// let request = "GET / HTTP/1.1";
+    /// measurement.measure(|| request.split(' ').count());
+    /// ```
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics<N>(mut self, name: N) -> Self
+    where
+        N: Into<metrics::SharedString>,
+    {
+        self.histogram = Some(name.into());
+        self
+    }
+
+    /// Streams every measured delta to `writer` as it is measured, one line of JSON per region:
+    ///
+    /// ```text
+    /// {"delta":1234,"ts":567890}
+    /// ```
+    ///
+    /// `ts` is the counter value at the end of the region, on the clock of
+    /// [`CyclesPerByte::start_raw`]. Setting the environment variable `CPB_STREAM` to anything but
+    /// `0` streams to stderr from [`CyclesPerByte::new`] on, for live monitoring on CI without
+    /// changing the harness.
+    ///
+    /// Lines are buffered, see [bookkeeping](CyclesPerByte#bookkeeping), and written out when the
+    /// buffer is full, at the first region to end 100ms after the previous write, and when the
+    /// last clone is dropped. Write errors are ignored.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().stream_to(std::io::stderr());
+    /// measurement.measure(|| ());
+    /// // The buffered lines are written out when the last clone is dropped.
+    /// drop(measurement);
+    /// ```
+    pub fn stream_to<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        self.stream = Some(Stream::new(Box::new(writer)));
+        self
+    }
+
+    /// Calls `callback` with the delta of every region as it ends, for telemetry sinks this crate
+    /// does not know about. Replaces any previous callback.
+    ///
+    /// The delta is the one criterion receives, after the overhead has been subtracted. The
+    /// callback runs on the thread that ended the region, see
+    /// [bookkeeping](CyclesPerByte#bookkeeping), so [`metadata::get`](crate::metadata::get) gives
+    /// its byte count. Clones share the callback and may call it from several threads at once.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let seen = Arc::new(Mutex::new(Vec::new()));
+    /// let sink = seen.clone();
+    /// let measurement = CyclesPerByte::new()
+    ///     .on_sample(Box::new(move |delta| sink.lock().unwrap().push(delta)));
+    /// let delta = measurement.measure(|| ());
+    /// assert_eq!(*seen.lock().unwrap(), [delta]);
+    /// ```
+    pub fn on_sample(mut self, callback: Box<dyn Fn(u64) + Send + Sync>) -> Self {
+        self.on_sample = Some(Arc::from(callback));
+        self
+    }
+
+    /// Stamps exported results with `commit`, the commit the measured code was built from, so
+    /// that archived numbers can be traced back to it.
+    ///
+    /// The commit goes into the header of [`CyclesPerByte::dump_binary`] files, see
+    /// [`read_binary_header`](crate::read_binary_header), and into baselines saved with
+    /// `CyclesPerByte::save_baseline`. Without it, the commit is taken from the `GIT_COMMIT` or,
+    /// failing that, the `VERGEN_GIT_SHA` environment variable when the results are exported,
+    /// and left out if neither is set. `VERGEN_GIT_SHA` is usually set at build time by a
+    /// `vergen` build script, in which case pass `env!("VERGEN_GIT_SHA")` here instead. An empty
+    /// `commit` exports none, even if the variables are set.
+    pub fn with_provenance<S: Into<String>>(mut self, commit: S) -> Self {
+        self.provenance = Some(commit.into());
+        self
+    }
+
+    /// Stamps [`CyclesPerByte::dump_binary`] files with `machine`, an identifier of the machine
+    /// that measured, so that samples from several machines stay apart when they are merged, see
+    /// [`CycleStats::merge_dumps`].
+    ///
+    /// Without it, the identifier is taken from the `CPB_MACHINE_ID` environment variable when
+    /// the samples are exported, and left out if it is not set. An empty `machine` exports none.
+    ///
+    /// [`CycleStats::merge_dumps`]: crate::CycleStats::merge_dumps
+    pub fn with_machine_id<S: Into<String>>(mut self, machine: S) -> Self {
+        self.machine = Some(machine.into());
+        self
+    }
+
+    /// Selects where the counter frequency used to convert cycles to time comes from, by default
+    /// [`FrequencySource::Auto`].
+    ///
+    /// Results are labelled with what the counter counts: `cycles` on x86, and `ticks` of the
+    /// generic timer on aarch64, which become `cycles (est.)` once a source other than `Auto` is
+    /// selected here.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{Backend, CyclesPerByte, FrequencySource};
+    ///
+    /// let measurement = CyclesPerByte::new().frequency_source(FrequencySource::Fixed(3_000_000_000));
+    /// assert_eq!(measurement.resolved_frequency_hz(), Some(3_000_000_000));
+    ///
+    /// # #[cfg(feature = "criterion")]
+    /// # {
+    /// use criterion::measurement::Measurement;
+    ///
+    /// let default = CyclesPerByte::new().formatter().format_value(8.0);
+    /// let auto = CyclesPerByte::new().frequency_source(FrequencySource::Auto);
+    /// let configured = measurement.formatter().format_value(8.0);
+    /// assert_eq!(auto.formatter().format_value(8.0), default);
+    /// assert_eq!((&*default, &*configured), ("8.0000 cycles", "8.0000 cycles"));
+    /// # }
+    ///
+    /// // The TSC frequency says nothing about a counter of unknown origin.
+    /// let measurement = CyclesPerByte::new().with_counter(|| 0);
+    /// assert_eq!(measurement.resolved_frequency_hz(), None);
+    ///
+    /// // Calibration gives an answer on any machine with a working TSC.
+    /// let measurement = CyclesPerByte::new().frequency_source(FrequencySource::Calibrated);
+    /// assert!(measurement.resolved_frequency_hz().unwrap() > 0);
+    /// ```
+    pub fn frequency_source(mut self, source: FrequencySource) -> Self {
+        self.frequency = source;
+        #[cfg(feature = "criterion")]
+        {
+            self.formatter.frequency_configured = source != FrequencySource::Auto;
+        }
+        self.resolved_frequency = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Checks that the configuration works on this host, without measuring anything, and returns
+    /// what was found: a pre-flight check for the setup step of a CI job, before a long run.
+    ///
+    /// The options that probe the host when they are selected record what failed: a backend that
+    /// fell back to `rdtsc`, failed pinning, and interrupt detection, throttling detection or a
+    /// PMU event that could not be set up, which are all [`ConfigError::Unavailable`]. On top of
+    /// that this checks the thresholds given to the measurement, that the selected counter
+    /// advances over a short busy loop and that a frequency source selected with
+    /// [`CyclesPerByte::frequency_source`] gives a frequency; [`FrequencySource::Auto`] may give
+    /// none, as for a custom counter. The first problem found is returned, in that order.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{Backend, ConfigError, CyclesPerByte, FrequencySource};
+    /// use std::sync::{
+    ///     atomic::{AtomicU64, Ordering},
+    ///     Arc,
+    /// };
+    ///
+    /// let report = CyclesPerByte::new().validate().expect("rdtsc works");
+    /// assert_eq!(report.backend, Backend::Rdtsc);
+    ///
+    /// let ticks = Arc::new(AtomicU64::new(0));
+    /// let counter = move || ticks.fetch_add(7, Ordering::Relaxed);
+    /// let report = CyclesPerByte::new().with_counter(counter.clone()).validate().unwrap();
+    /// assert_eq!((report.backend, report.resolution, report.frequency_hz), (Backend::Custom, 7, None));
+    ///
+    /// let stuck = CyclesPerByte::new().with_counter(|| 42);
+    /// assert_eq!(stuck.validate(), Err(ConfigError::CounterStuck(Backend::Custom)));
+    ///
+    /// let unknown = CyclesPerByte::new()
+    ///     .with_counter(counter.clone())
+    ///     .frequency_source(FrequencySource::Cpuid);
+    /// assert_eq!(
+    ///     unknown.validate(),
+    ///     Err(ConfigError::FrequencyUnavailable(FrequencySource::Cpuid))
+    /// );
+    ///
+    /// #[cfg(target_os = "linux")]
+    /// {
+    ///     let invalid = CyclesPerByte::new().detect_tsc_drift(-1.0);
+    ///     assert_eq!(
+    ///         invalid.validate(),
+    ///         Err(ConfigError::InvalidThreshold { option: "detect_tsc_drift", value: -1.0 })
+    ///     );
+    ///
+    ///     let unpinned = CyclesPerByte::new().pinned_to(1023);
+    ///     let error = unpinned.validate().unwrap_err();
+    ///     assert!(matches!(error, ConfigError::Unavailable { feature: "pinning", .. }), "{}", error);
+    /// }
+    /// ```
+    pub fn validate(&self) -> Result<ValidationReport, ConfigError> {
+        if let Some(error) = self.setup_errors.first() {
+            return Err(error.clone());
+        }
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let Some(ref throttling) = self.throttling {
+            if throttling.threshold.is_nan() || throttling.threshold < 0.0 {
+                return Err(ConfigError::InvalidThreshold {
+                    option: "detect_throttling",
+                    value: throttling.threshold,
+                });
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ref tsc_drift) = self.tsc_drift {
+            if tsc_drift.tolerance.is_nan() || tsc_drift.tolerance < 0.0 {
+                return Err(ConfigError::InvalidThreshold {
+                    option: "detect_tsc_drift",
+                    value: tsc_drift.tolerance,
+                });
+            }
+        }
+
+        let start = self.read();
+        let mut spin = 0u64;
+        while spin < 10_000 {
+            spin = black_box(spin + 1);
+        }
+        if self.read_end() <= start {
+            return Err(ConfigError::CounterStuck(self.active_backend()));
+        }
+        let mut resolution = 0;
+        for _ in 0..100 {
+            let first = self.read();
+            let delta = self.read().saturating_sub(first);
+            if delta != 0 && (resolution == 0 || delta < resolution) {
+                resolution = delta;
+            }
+        }
+
+        let frequency_hz = self.resolved_frequency_hz();
+        if frequency_hz.is_none() && self.frequency != FrequencySource::Auto {
+            return Err(ConfigError::FrequencyUnavailable(self.frequency));
+        }
+        Ok(ValidationReport {
+            backend: self.active_backend(),
+            resolution,
+            frequency_hz,
+            invariant_tsc: detect::invariant_tsc(),
+            cpu: affinity::current_cpu().ok(),
+        })
+    }
+
+    /// Keeps the cpb of each benchmark, under the id set with [`metadata::set_id`], for
+    /// [`CyclesPerByte::cpb_by_id`] and baselines.
+    ///
+    /// The formatter is not told which benchmark it formats, so set the id in the benchmark
+    /// closure where the [`metadata`] counts would go. A result is filed when criterion formats
+    /// its throughput, which is only per byte if the throughput is `Throughput::Bytes` or
+    /// `Throughput::BytesDecimal`, or bytes were set with [`metadata::set_bytes_and_elements`].
+    /// The cpb is criterion's point estimate of the mean, as printed on the `thrpt` line.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "criterion")]
+    /// # {
+    /// use criterion::{measurement::Measurement, Criterion, Throughput};
+    /// use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+    ///
+    /// fn bench(c: &mut Criterion<CyclesPerByte>) {
+    ///     let mut group = c.benchmark_group("xor");
+    ///     for size in [16, 4096] {
+    ///         let data = vec![0u8; size];
+    ///         group.throughput(Throughput::Bytes(size as u64));
+    ///         group.bench_function(size.to_string(), |b| {
+    ///             metadata::set_id(format!("xor/{}", size));
+    ///             b.iter(|| data.iter().fold(0, |acc, x| acc ^ x))
+    ///         });
+    ///     }
+    ///     group.finish();
+    ///     metadata::clear();
+    /// }
+    ///
+    /// let measurement = CyclesPerByte::new().track_cpb();
+    /// let formatter = measurement.formatter();
+    ///
+    /// // What criterion formats for a result of 128 [120, 140] cycles per iteration.
+    /// metadata::set_id("xor/16");
+    /// for cycles in [140.0, 128.0, 120.0] {
+    ///     formatter.format_throughput(&Throughput::Bytes(16), cycles);
+    /// }
+    /// metadata::clear();
+    ///
+    /// assert_eq!(measurement.cpb_by_id()["xor/16"], 8.0);
+    /// # }
+    /// ```
+    ///
+    /// [`metadata`]: crate::metadata
+    /// [`metadata::set_bytes_and_elements`]: crate::metadata::set_bytes_and_elements
+    /// [`metadata::set_id`]: crate::metadata::set_id
+    pub fn track_cpb(mut self) -> Self {
+        let tracked = Arc::new(Tracked::default());
+        #[cfg(feature = "criterion")]
+        {
+            self.formatter.tracked = Some(tracked.clone());
+        }
+        self.tracked = Some(tracked);
+        self
+    }
+
+    /// Controls whether `Throughput::BytesDecimal` results are labeled `cpb (decimal)` (the
+    /// default) or plain `cpb`.
+    ///
+    /// The cost per byte is the same however the byte count is interpreted, so dropping the suffix
+    /// gives a single `cpb` token that is easier to grep and parse.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let throughput = Throughput::BytesDecimal(64);
+    ///
+    /// let measurement = CyclesPerByte::new();
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(formatter.format_throughput(&throughput, 128.0), "2.0000 cpb (decimal)");
+    /// assert_eq!(formatter.scale_throughputs(128.0, &throughput, &mut [128.0]), "cpb (decimal)");
+    ///
+    /// let measurement = CyclesPerByte::new().decimal_suffix(false);
+    /// let formatter = measurement.formatter();
+    /// let mut values = [128.0, 256.0];
+    /// assert_eq!(formatter.format_throughput(&throughput, 128.0), "2.0000 cpb");
+    /// assert_eq!(formatter.scale_throughputs(128.0, &throughput, &mut values), "cpb");
+    /// assert_eq!(values, [2.0, 4.0]);
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn decimal_suffix(mut self, enabled: bool) -> Self {
+        self.formatter.decimal_suffix = enabled;
+        self
+    }
+
+    /// Controls whether units are marked with an asterisk (`cpb*`) to flag results that are only
+    /// valid for same-run comparisons. This is enabled by [`CyclesPerByte::relative_mode`] and off
+    /// otherwise.
+    #[cfg(feature = "criterion")]
+    pub fn relative_marker(mut self, enabled: bool) -> Self {
+        self.formatter.relative_marker = enabled;
+        self
+    }
+
+    /// Controls whether throughput results also show the total cycle count, e.g.
+    /// `131072.0000 cycles, 2.0000 cpb`. Off by default.
+    ///
+    /// criterion formats its `time` line with cycles and, when a throughput is set, a separate
+    /// `thrpt` line with cpb. Without plots the two are easy to miss side by side, so this puts
+    /// both on the `thrpt` line. criterion passes the same per-iteration cycle count to both
+    /// lines, which is what makes the combination possible: the `time` line and plots stay as
+    /// they are.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().combined_units(true);
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Bytes(65536), 131072.0),
+    ///     "131072.0000 cycles, 2.0000 cpb"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(4), 131072.0),
+    ///     "131072.0000 cycles, 32768.0000 c/e"
+    /// );
+    /// assert_eq!(formatter.format_value(131072.0), "131072.0000 cycles");
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn combined_units(mut self, enabled: bool) -> Self {
+        self.formatter.combined_units = enabled;
+        self
+    }
+
+    /// Separates thousands in cycle counts with `separator`, e.g. `1,073,741,824.0000 cycles` for
+    /// `Some(',')`. Per-byte and per-element values are left as they are, and so is the output for
+    /// machines (CSV and JSON). The default is `None`, which keeps counts easy to parse.
+    ///
+    /// ```rust
+    /// use criterion::measurement::Measurement;
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().thousands_separator(Some(','));
+    /// assert_eq!(measurement.formatter().format_value(1073741824.0), "1,073,741,824.0000 cycles");
+    /// assert_eq!(measurement.formatter().format_value(999.5), "999.5000 cycles");
+    ///
+    /// let measurement = CyclesPerByte::new().thousands_separator(Some('_'));
+    /// assert_eq!(measurement.formatter().format_value(-1234.0), "-1_234.0000 cycles");
+    ///
+    /// let measurement = CyclesPerByte::new();
+    /// assert_eq!(measurement.formatter().format_value(1073741824.0), "1073741824.0000 cycles");
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn thousands_separator(mut self, separator: Option<char>) -> Self {
+        self.formatter.thousands_separator = separator;
+        self
+    }
+
+    /// Appends the coefficient of variation of the measured deltas to throughput results, e.g.
+    /// `3.0100 cpb (cv=1.2%)`, enabling [`CyclesPerByte::streaming_stats`] if needed. Off by
+    /// default.
+    ///
+    /// A few percent is typical for a stable measurement. The deltas are of whole samples, so use
+    /// `SamplingMode::Flat` to give every sample the same iteration count, and call
+    /// [`CyclesPerByte::clear_samples`] on a clone between benchmarks.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().coefficient_of_variation(true);
+    /// for _ in 0..100 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// let result = measurement.formatter().format_throughput(&Throughput::Bytes(10), 100.0);
+    /// assert!(result.starts_with("10.0000 cpb (cv="), "{}", result);
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn coefficient_of_variation(mut self, enabled: bool) -> Self {
+        if enabled {
+            self = self.streaming_stats();
+            self.formatter.variation = self.stats.clone();
+        } else {
+            self.formatter.variation = None;
+        }
+        self
+    }
+
+    /// Controls whether cpb results are annotated with the 99th percentile of
+    /// [`CyclesPerByte::p99_cpb`], e.g. `2.0100 cpb (p99: 3.4000)`. Off by default; turning it on
+    /// also enables [`CyclesPerByte::streaming_stats`].
+    ///
+    /// The percentile accumulates over every benchmark measured with this measurement or its
+    /// clones, so call [`CyclesPerByte::clear_samples`] on a clone between benchmarks.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().p99_annotation(true);
+    /// for _ in 0..100 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// let result = measurement.formatter().format_throughput(&Throughput::Bytes(100), 201.0);
+    /// assert!(result.starts_with("2.0100 cpb (p99: "), "{}", result);
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn p99_annotation(mut self, enabled: bool) -> Self {
+        if enabled {
+            self = self.streaming_stats();
+            self.formatter.p99 = self.stats.clone();
+        } else {
+            self.formatter.p99 = None;
+        }
+        self
+    }
+
+    /// Also reports cycles per block of `size` bytes for byte throughputs, e.g.
+    /// `2.0000 cpb (32.0000 c/blk)` for 16-byte cipher blocks. 0, the default, turns this off.
+    ///
+    /// A byte count that is not a multiple of `size` is rounded up to whole blocks, as padding a
+    /// partial block costs as much as a full one. The block count is not used where a per-element
+    /// count is already shown, as with [`metadata::set_bytes_and_elements`].
+    ///
+    /// ```rust
+    /// # fn aes128_encrypt_blocks(_key: &[u8; 16], data: &mut [u8]) { data.iter_mut().for_each(|b| *b ^= 0x63); }
+    /// use criterion::{black_box, measurement::Measurement, Criterion, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// fn bench(c: &mut Criterion<CyclesPerByte>) {
+    ///     let key = [0u8; 16];
+    ///     let mut data = vec![0u8; 4096];
+    ///     let mut group = c.benchmark_group("aes-128");
+    ///     group.throughput(Throughput::Bytes(data.len() as u64));
+    ///     group.bench_function("ecb", |b| {
+    ///         b.iter(|| aes128_encrypt_blocks(black_box(&key), black_box(&mut data)))
+    ///     });
+    ///     group.finish();
+    /// }
+    ///
+    /// let measurement = CyclesPerByte::new().block_size(16);
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Bytes(4096), 8192.0),
+    ///     "2.0000 cpb (32.0000 c/blk)"
+    /// );
+    /// // 40 bytes are padded to 3 blocks.
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Bytes(40), 120.0),
+    ///     "3.0000 cpb (40.0000 c/blk)"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(4), 120.0),
+    ///     "120.0000 cycles/4"
+    /// );
+    /// ```
+    ///
+    /// [`metadata::set_bytes_and_elements`]: crate::metadata::set_bytes_and_elements
+    #[cfg(feature = "criterion")]
+    pub fn block_size(mut self, size: u64) -> Self {
+        self.formatter.block_size = size;
+        self
+    }
+
+    /// Reports element throughputs as cycles per operation with an SI prefix, e.g.
+    /// `1.23 Mcyc/op`. Off by default.
+    ///
+    /// Meant for public-key operations that take millions of cycles: set the operations per
+    /// iteration with [`Throughput::Elements`]; a count of 0 reports the total cycles, e.g.
+    /// `1.23 Mcyc (0 ops)`. Byte throughputs stay in cpb.
+    ///
+    /// [`Throughput::Elements`]: criterion::Throughput::Elements
+    ///
+    /// ```rust
+    /// use criterion::{black_box, measurement::Measurement, Criterion, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// /// Stands in for an RSA signature: a chain of modular multiplications.
+    /// fn sign(message: u64, key: u64) -> u64 {
+    ///     let modulus = 0xffff_ffff_ffff_ffc5u128;
+    ///     let mut signature = u128::from(message);
+    ///     for _ in 0..1000 {
+    ///         signature = signature * u128::from(key) % modulus;
+    ///     }
+    ///     signature as u64
+    /// }
+    ///
+    /// fn bench(c: &mut Criterion<CyclesPerByte>) {
+    ///     let mut group = c.benchmark_group("rsa");
+    ///     group.throughput(Throughput::Elements(1));
+    ///     group.bench_function("sign", |b| b.iter(|| sign(black_box(42), black_box(65537))));
+    ///     group.finish();
+    /// }
+    ///
+    /// let measurement = CyclesPerByte::new().per_operation(true);
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(1), 1_234_567.0),
+    ///     "1.23 Mcyc/op"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(4), 4_938_268.0),
+    ///     "1.23 Mcyc/op"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(1), 999_999.0),
+    ///     "1.00 Mcyc/op"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(2), 1700.0),
+    ///     "850.00 cyc/op"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(1), 25e9),
+    ///     "25.00 Gcyc/op"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(0), 1_234_567.0),
+    ///     "1.23 Mcyc (0 ops)"
+    /// );
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Bytes(64), 128.0),
+    ///     "2.0000 cpb"
+    /// );
+    ///
+    /// let mut values = [2_000_000.0, 3_000_000.0];
+    /// let unit = formatter.scale_throughputs(2_500_000.0, &Throughput::Elements(2), &mut values);
+    /// assert_eq!((unit, values), ("Mcyc/op", [1.0, 1.5]));
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn per_operation(mut self, enabled: bool) -> Self {
+        self.formatter.per_operation = enabled;
+        self
+    }
+
+    /// Compares cpb results with the theoretical minimum `cpb` of the algorithm, appending the
+    /// efficiency, e.g. `1.5000 cpb (33% of peak)` for a bound of 0.5 cpb. Off by default, and
+    /// turned off again by a bound that is not positive.
+    ///
+    /// The efficiency is the bound divided by the measured cpb. Results below the bound are marked
+    /// `(beyond peak)` rather than given as more than 100%, since they mean the bound or the
+    /// byte count is wrong. Results that are not positive get no annotation.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().theoretical_cpb(0.5);
+    /// let formatter = measurement.formatter();
+    /// let bytes = Throughput::Bytes(100);
+    /// assert_eq!(formatter.format_throughput(&bytes, 150.0), "1.5000 cpb (33% of peak)");
+    /// assert_eq!(formatter.format_throughput(&bytes, 50.0), "0.5000 cpb (100% of peak)");
+    /// assert_eq!(formatter.format_throughput(&bytes, 62.5), "0.6250 cpb (80% of peak)");
+    /// assert_eq!(formatter.format_throughput(&bytes, 40.0), "0.4000 cpb (beyond peak)");
+    /// assert_eq!(formatter.format_throughput(&bytes, 0.0), "0.0000 cpb");
+    /// assert_eq!(
+    ///     formatter.format_throughput(&Throughput::Elements(4), 150.0),
+    ///     "150.0000 cycles/4"
+    /// );
+    ///
+    /// let measurement = CyclesPerByte::new().theoretical_cpb(0.0);
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(formatter.format_throughput(&bytes, 150.0), "1.5000 cpb");
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn theoretical_cpb(mut self, cpb: f64) -> Self {
+        self.formatter.theoretical_cpb = Some(cpb).filter(|&cpb| cpb > 0.0);
+        self
+    }
+
+    /// Flags cpb results outside of `min..=max` with a warning on stderr and in the result, and
+    /// reports the median of the recorded samples in their place if recording with
+    /// [`CyclesPerByte::record_samples`].
+    ///
+    /// This only keeps one contaminated run from ruining a dashboard: a substituted result reads
+    /// e.g. `2.0500 cpb (median, measured 10000.0000 cpb)`, or `10000.0000 cpb (implausible)`
+    /// without a recording. Only the formatted results change.
+    ///
+    /// Panics if `min` is greater than `max`.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let bytes = Throughput::Bytes(64);
+    /// let measurement = CyclesPerByte::new().plausible_cpb_range(1.0, 4.0);
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(formatter.format_throughput(&bytes, 128.0), "2.0000 cpb");
+    /// assert_eq!(formatter.format_throughput(&bytes, 64.0), "1.0000 cpb");
+    /// assert_eq!(formatter.format_throughput(&bytes, 32.0), "0.5000 cpb (implausible)");
+    /// assert_eq!(
+    ///     formatter.format_throughput(&bytes, 640_000.0),
+    ///     "10000.0000 cpb (implausible)"
+    /// );
+    ///
+    /// let readings = Arc::new(Mutex::new(vec![0, 128, 0, 131, 0, 640_000]));
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || readings.lock().unwrap().remove(0))
+    ///     .record_samples()
+    ///     .plausible_cpb_range(1.0, 4.0);
+    /// for _ in 0..3 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// assert_eq!(
+    ///     measurement.formatter().format_throughput(&bytes, 640_000.0),
+    ///     "2.0469 cpb (median, measured 10000.0000 cpb)"
+    /// );
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn plausible_cpb_range(mut self, min: f64, max: f64) -> Self {
+        assert!(min <= max, "plausible cpb range {}..{} is empty", min, max);
+        self.formatter.plausible_cpb = Some((min, max));
+        self
+    }
+
+    /// Controls whether cycle counts are followed by the cpb of the size encoded in the benchmark
+    /// id, for benchmarks without a `Throughput`. Off by default.
+    ///
+    /// criterion only prints a throughput line, and only asks for cpb, if the benchmark has a
+    /// `Throughput`. With this enabled, a benchmark whose id set with [`metadata::set_id`] ends in
+    /// a size, as parsed by [`metadata::infer_bytes_from_id`], gets its cpb on the time line
+    /// instead, e.g. `32768.0000 cycles (2.0000 cpb)` for `"aes/16384"`. Counts set with
+    /// [`metadata::set_bytes_and_elements`] take precedence, as does compact output.
+    ///
+    /// ```rust
+    /// use criterion::measurement::Measurement;
+    /// use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+    ///
+    /// let measurement = CyclesPerByte::new().bytes_from_id(true);
+    /// let formatter = measurement.formatter();
+    ///
+    /// metadata::set_id("aes/16384");
+    /// assert_eq!(formatter.format_value(32768.0), "32768.0000 cycles (2.0000 cpb)");
+    /// metadata::set_id("aes/encrypt");
+    /// assert_eq!(formatter.format_value(32768.0), "32768.0000 cycles");
+    /// metadata::clear();
+    /// assert_eq!(formatter.format_value(32768.0), "32768.0000 cycles");
+    /// ```
+    ///
+    /// [`metadata::infer_bytes_from_id`]: crate::metadata::infer_bytes_from_id
+    /// [`metadata::set_bytes_and_elements`]: crate::metadata::set_bytes_and_elements
+    /// [`metadata::set_id`]: crate::metadata::set_id
+    #[cfg(feature = "criterion")]
+    pub fn bytes_from_id(mut self, enabled: bool) -> Self {
+        self.formatter.bytes_from_id = enabled;
+        self
+    }
+
+    /// Controls whether results are printed as bare numbers without any unit, for dense tables
+    /// that are post-processed into a grid. Off by default, unless the `CPB_COMPACT` environment
+    /// variable is set to anything other than `0` when the measurement is created.
+    ///
+    /// Cycle counts become e.g. `1024.0000` and throughput results the single number per byte or
+    /// per element that would otherwise come first, e.g. `2.0000` for
+    /// `2.0000 cpb (32.0000 c/blk)`. Annotations such as the coefficient of variation and the
+    /// setup cycles are left out, and so is the total cycle count of
+    /// [`CyclesPerByte::combined_units`]. The units criterion puts on plot axes and in its
+    /// machine-readable output stay as they are.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// for (throughput, value) in [
+    ///     (Throughput::Bytes(64), 128.0),
+    ///     (Throughput::BytesDecimal(64), 128.0),
+    ///     (Throughput::Elements(4), 128.0),
+    /// ] {
+    ///     let full = CyclesPerByte::new().block_size(16).compact(false);
+    ///     let compact = CyclesPerByte::new().block_size(16).compact(true);
+    ///     let full = full.formatter().format_throughput(&throughput, value);
+    ///     let compact = compact.formatter().format_throughput(&throughput, value);
+    ///     assert!(compact.chars().all(|c| c.is_ascii_digit() || c == '.'), "{}", compact);
+    ///     assert!(full.starts_with(&format!("{} ", compact)), "{} {}", full, compact);
+    /// }
+    ///
+    /// let measurement = CyclesPerByte::new().compact(true).thousands_separator(Some(','));
+    /// assert_eq!(measurement.formatter().format_value(1048576.0), "1,048,576.0000");
+    /// ```
+    #[cfg(feature = "criterion")]
+    pub fn compact(mut self, enabled: bool) -> Self {
+        self.formatter.compact = enabled;
+        self
+    }
+
+    /// Measures how many cycles the counter reads at the start and at the end of a region add to
+    /// it, and subtracts them from every delta measured from now on, saturating at 0. Sub-regions
+    /// marked with [`tic()`](crate::tic) and [`toc()`](crate::toc) are corrected each.
+    ///
+    /// The split between [`CyclesPerByte::start_overhead`] and [`CyclesPerByte::end_overhead`] is
+    /// estimated from back-to-back reads of each kind. Select the backend first. Out-of-order
+    /// execution overlaps the reads with the code, so tiny regions can come out a few cycles short.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{Backend, CyclesPerByte};
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// // Every read costs 30 cycles, so an empty region measures 30.
+    /// let counter = AtomicU64::new(0);
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || counter.fetch_add(30, Ordering::Relaxed))
+    ///     .compensate_overhead();
+    /// assert_eq!((measurement.start_overhead(), measurement.end_overhead()), (15, 15));
+    /// assert_eq!(measurement.measure(|| ()), 0);
+    ///
+    /// let serialized = CyclesPerByte::new()
+    ///     .backend(Backend::Serialized)
+    ///     .compensate_overhead();
+    /// assert!(serialized.start_overhead() + serialized.end_overhead() < 10_000);
+    /// ```
+    pub fn compensate_overhead(mut self) -> Self {
+        let min_over_runs =
+            |region: &dyn Fn() -> u64| (0..HARNESS_FLOOR_RUNS).map(|_| region()).min().unwrap_or(0);
+        let start_read = min_over_runs(&|| {
+            let first = self.read();
+            self.read().saturating_sub(first)
+        });
+        let end_read = min_over_runs(&|| {
+            let first = self.read_end();
+            self.read_end().saturating_sub(first)
+        });
+        let empty = min_over_runs(&|| {
+            let start = self.read();
+            self.read_end().saturating_sub(start)
+        });
+
+        let reads = u128::from(start_read) + u128::from(end_read);
+        let start = (u128::from(empty) * u128::from(start_read))
+            .checked_div(reads)
+            .map_or(empty / 2, |start| start as u64);
+        self.overhead = (start, empty - start);
+        self
+    }
+}
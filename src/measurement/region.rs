@@ -0,0 +1,385 @@
+//! Reading the counter around a region, for criterion and for direct use.
+
+use super::{state::Migration, CyclesPerByte, HARNESS_FLOOR_RUNS};
+use crate::{manual, now};
+#[cfg(feature = "criterion")]
+use criterion::measurement::{Measurement, ValueFormatter};
+use std::{hint::black_box, sync::atomic::Ordering};
+
+impl CyclesPerByte {
+    /// Returns the raw time-stamp counter at the start of a region, for correlating it with
+    /// external trace events.
+    ///
+    /// This is [`now()`], the value of `rdtsc`, whatever backend is selected, so it is on the same
+    /// clock as the TSC timestamps of Intel PT traces or ETW sessions using the cycle counter
+    /// clock, and converts to `perf` time with the `time_zero`, `time_mult` and `time_shift`
+    /// fields of a perf event's mmap page. Pair it with [`CyclesPerByte::end_raw`].
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let mut previous_end = 0;
+    /// for _ in 0..1000 {
+    ///     let start = CyclesPerByte::start_raw();
+    ///     let (end, delta) = CyclesPerByte::end_raw(start);
+    ///     assert!(start >= previous_end && end >= start);
+    ///     assert_eq!(delta, end - start);
+    ///     previous_end = end;
+    /// }
+    /// ```
+    #[inline]
+    pub fn start_raw() -> u64 {
+        now()
+    }
+
+    /// Returns the raw time-stamp counter at the end of a region started at `start`, and the
+    /// cycles since then: `(end, end - start)`.
+    ///
+    /// Both values are unaffected by the backend and the other options, and nothing is recorded.
+    /// The TSC only increases on one core, and across cores if it is invariant and synchronized,
+    /// which it is on all recent hardware; if the thread moved to a core whose TSC is behind, the
+    /// delta is 0 rather than wrapping around.
+    #[inline]
+    pub fn end_raw(start: u64) -> (u64, u64) {
+        let end = now();
+        (end, end.saturating_sub(start))
+    }
+
+    /// Reads the selected counter.
+    #[inline]
+    pub fn read(&self) -> u64 {
+        self.counter.read()
+    }
+
+    /// Reads the counter at the end of a region.
+    #[inline]
+    pub(super) fn read_end(&self) -> u64 {
+        self.counter.read_end()
+    }
+
+    /// Measures a single call of `f` and returns the cycles it took.
+    ///
+    /// This is the region criterion measures around each batch, for use without criterion: the
+    /// delta is checked, recorded and added to the statistics like any sample, and
+    /// [`tic()`](crate::tic) and [`toc()`](crate::toc) inside `f` narrow it down. The result of `f`
+    /// goes through `black_box` so that computing it cannot be optimized away.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().record_samples();
+    /// let cycles = measurement.measure(|| (0..64u32).sum::<u32>());
+    /// assert_eq!(measurement.samples(), vec![cycles]);
+    /// ```
+    #[inline]
+    pub fn measure<F, R>(&self, f: F) -> u64
+    where
+        F: FnOnce() -> R,
+    {
+        let start = self.begin();
+        black_box(f());
+        self.stop(start)
+    }
+
+    /// Measures a call of `f` like [`CyclesPerByte::measure`], and measures it again while the
+    /// cycles exceed `threshold` times the running median of the clean samples, up to
+    /// `max_retries` more times. Returns the first clean sample, or `None` if every attempt
+    /// exceeded the threshold.
+    ///
+    /// This keeps interrupted calls out of constant-time comparisons. The first call is always
+    /// clean, and rejected attempts are still recorded. The result cuts off the upper tail, so
+    /// filter both classes of inputs with the same measurement and a threshold well above the
+    /// difference to detect.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// // Calls of 100 cycles, with interruptions inflating some of them.
+    /// let deltas = [100, 100, 2_000, 100, 3_000, 5_000, 4_000, 100];
+    /// let readings = Arc::new(Mutex::new(deltas.iter().flat_map(|&d| [0, d]).collect::<Vec<_>>()));
+    /// let remaining = readings.clone();
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || readings.lock().unwrap().remove(0))
+    ///     .record_samples();
+    ///
+    /// assert_eq!(measurement.measure_clean(|| (), 1.5, 2), Some(100));
+    /// assert_eq!(measurement.measure_clean(|| (), 1.5, 2), Some(100));
+    /// // Retried once after 2000.
+    /// assert_eq!(measurement.measure_clean(|| (), 1.5, 2), Some(100));
+    /// // 3000, 5000 and 4000 exhaust the two retries.
+    /// assert_eq!(measurement.measure_clean(|| (), 1.5, 2), None);
+    /// assert_eq!(measurement.measure_clean(|| (), 1.5, 0), Some(100));
+    /// assert!(remaining.lock().unwrap().is_empty());
+    /// assert_eq!(measurement.samples(), deltas);
+    /// ```
+    pub fn measure_clean<F, R>(&self, f: F, threshold: f64, max_retries: u32) -> Option<u64>
+    where
+        F: Fn() -> R,
+    {
+        let limit = self
+            .clean_median
+            .lock()
+            .unwrap()
+            .get()
+            .map(|median| median * threshold);
+        for _ in 0..=max_retries {
+            let delta = self.measure(&f);
+            if limit.map_or(true, |limit| delta as f64 <= limit) {
+                self.clean_median.lock().unwrap().push(delta);
+                return Some(delta);
+            }
+        }
+        None
+    }
+
+    /// Measures two calls of `f` and returns `(cold, warm)`: the cycles of the first call, and of
+    /// the second, which finds the code and data of `f` in the caches.
+    ///
+    /// Nothing is evicted before the first call, so it is only cold if `f` did not run earlier in
+    /// the process. Both calls are recorded like [`CyclesPerByte::measure`].
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let table: Vec<u64> = (0..4096).collect();
+    /// let measurement = CyclesPerByte::new().record_samples();
+    /// let (cold, warm) = measurement.measure_warm_cold(|| table.iter().sum::<u64>());
+    /// assert_eq!(measurement.samples(), [cold, warm]);
+    /// ```
+    pub fn measure_warm_cold<F, R>(&self, f: F) -> (u64, u64)
+    where
+        F: Fn() -> R,
+    {
+        let cold = self.measure(&f);
+        let warm = self.measure(&f);
+        (cold, warm)
+    }
+
+    /// Measures `n` back-to-back calls of `f` in one region and returns the cycles per call, for
+    /// operations too short to measure one at a time. The region is a single sample.
+    ///
+    /// The loop count and the results go through `black_box`, but consecutive calls can still
+    /// overlap, so this is their throughput rather than their latency; pass the inputs through
+    /// `black_box` inside `f`.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is 0.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{
+    ///     atomic::{AtomicU64, Ordering},
+    ///     Arc,
+    /// };
+    ///
+    /// // A counter that only advances by 40 cycles per call of the operation.
+    /// let work = Arc::new(AtomicU64::new(0));
+    /// let counter = work.clone();
+    /// let measurement =
+    ///     CyclesPerByte::new().with_counter(move || counter.load(Ordering::Relaxed));
+    /// let op = || work.fetch_add(40, Ordering::Relaxed);
+    ///
+    /// for n in [1, 10, 1000] {
+    ///     assert_eq!(measurement.measure_repeated(n, op), 40.0);
+    /// }
+    /// ```
+    pub fn measure_repeated<F, R>(&self, n: u64, mut f: F) -> f64
+    where
+        F: FnMut() -> R,
+    {
+        assert!(n != 0, "criterion-cycles-per-byte: cannot measure 0 calls");
+        let start = self.begin();
+        for _ in 0..black_box(n) {
+            black_box(f());
+        }
+        self.stop(start) as f64 / n as f64
+    }
+
+    /// Returns the fewest cycles this measurement reports for a sample of one empty iteration,
+    /// the floor under every result.
+    ///
+    /// A sample of `n` iterations reports at least `harness_floor() / n` more per iteration than
+    /// the operation costs, which can dominate operations of a few dozen cycles.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// // Every read advances the counter by 24, as if each read cost that much.
+    /// let counter = AtomicU64::new(0);
+    /// let measurement =
+    ///     CyclesPerByte::new().with_counter(move || counter.fetch_add(24, Ordering::Relaxed));
+    /// assert_eq!(measurement.harness_floor(), 24);
+    ///
+    /// assert!(CyclesPerByte::new().harness_floor() < 10_000);
+    /// ```
+    pub fn harness_floor(&self) -> u64 {
+        (0..HARNESS_FLOOR_RUNS)
+            .map(|_| {
+                let start = self.read();
+                for _ in 0..black_box(1u64) {
+                    black_box(());
+                }
+                self.read_end().saturating_sub(start)
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Returns the cycles the start of a region is estimated to add to it, see
+    /// [`CyclesPerByte::compensate_overhead`], or 0 if it was not calibrated.
+    pub fn start_overhead(&self) -> u64 {
+        self.overhead.0
+    }
+
+    /// Returns the cycles the end of a region is estimated to add to it, see
+    /// [`CyclesPerByte::compensate_overhead`], or 0 if it was not calibrated.
+    pub fn end_overhead(&self) -> u64 {
+        self.overhead.1
+    }
+
+    /// Starts a region and returns the counter value it starts at.
+    #[inline]
+    fn begin(&self) -> u64 {
+        if let Some(ref migration) = self.migration {
+            if let Some(aux) = migration.check() {
+                migration.region.store(aux, Ordering::Relaxed);
+            }
+        }
+        // The event counter is read outside of the interrupt check, whose calibration only covers
+        // the cycle counter reads.
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let Some(ref pmu) = self.pmu {
+            pmu.start.store((pmu.counter)(), Ordering::Relaxed);
+        }
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let Some(ref check) = self.interrupts {
+            check.start.store((check.counter)(), Ordering::Relaxed);
+        }
+        manual::reset(&self.counter);
+        self.read()
+    }
+
+    /// Ends a region started at counter value `start` and returns its length.
+    #[inline]
+    fn stop(&self, start: u64) -> u64 {
+        let end = self.read_end();
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let Some(ref check) = self.interrupts {
+            let instructions =
+                (check.counter)().saturating_sub(check.start.load(Ordering::Relaxed));
+            if instructions > check.threshold {
+                check.suspect.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        let events = self
+            .pmu
+            .as_ref()
+            .map(|pmu| (pmu.counter)().saturating_sub(pmu.start.load(Ordering::Relaxed)));
+        let marked = manual::take();
+        // Every sub-region paid for its own pair of reads.
+        let overhead = self.overhead.0 + self.overhead.1;
+        let delta = match marked.data {
+            Some(data) => data.saturating_sub(overhead.saturating_mul(marked.data_regions)),
+            None => self.subtraction.delta(start, end).saturating_sub(overhead),
+        };
+        manual::take_phases(|from, to, cycles| {
+            let mut phases = self.phases.lock().unwrap();
+            match phases.iter_mut().find(|(f, t, _)| f == from && t == to) {
+                Some(phase) => phase.2 = phase.2.saturating_add(cycles),
+                None => phases.push((from.to_owned(), to.to_owned(), cycles)),
+            }
+        });
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let (Some(ref pmu), Some(events)) = (&self.pmu, events) {
+            pmu.add(delta, events);
+        }
+        if marked.setup_regions != 0 {
+            let setup = marked
+                .setup_cycles
+                .saturating_sub(overhead.saturating_mul(marked.setup_regions));
+            self.setup.cycles.fetch_add(setup, Ordering::Relaxed);
+            self.setup
+                .regions
+                .fetch_add(marked.setup_regions, Ordering::Relaxed);
+        }
+        let mut crossed = false;
+        #[cfg(all(feature = "msr", target_os = "linux"))]
+        if let Some(ref throttling) = self.throttling {
+            throttling.sample();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ref tsc_drift) = self.tsc_drift {
+            tsc_drift.sample(end);
+        }
+        if let Some(ref migration) = self.migration {
+            crossed = migration.check().is_some_and(|aux| {
+                aux != migration.region.swap(Migration::UNSET, Ordering::Relaxed)
+            });
+        }
+        if let Some(ref discards) = self.discards {
+            discards.samples.fetch_add(1, Ordering::Relaxed);
+            if end < start || crossed {
+                discards.discarded.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(ref samples) = self.samples {
+            samples.lock().unwrap().push(start, delta);
+        }
+        if let Some(ref stats) = self.stats {
+            stats.lock().unwrap().push(delta);
+        }
+        if let Some(ref budget) = self.budget {
+            budget
+                .spent
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spent| {
+                    Some(spent.saturating_add(delta))
+                })
+                .ok();
+        }
+        if let Some(ref stream) = self.stream {
+            stream.lock().unwrap().push(delta, end);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(ref name) = self.histogram {
+            metrics::histogram!(name.clone()).record(delta as f64);
+        }
+        if let Some(ref on_sample) = self.on_sample {
+            on_sample(delta);
+        }
+        delta
+    }
+}
+
+#[cfg(feature = "criterion")]
+impl Measurement for CyclesPerByte {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> Self::Intermediate {
+        self.begin()
+    }
+
+    fn end(&self, i: Self::Intermediate) -> Self::Value {
+        self.stop(i)
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1.saturating_add(*v2)
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &self.formatter
+    }
+}
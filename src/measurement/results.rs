@@ -0,0 +1,722 @@
+//! What [`CyclesPerByte`] has measured, as statistics, files and checks.
+
+use super::{
+    state::Recording, Backend, CyclesPerByte, Discarded, FrequencySource, Source, COMMIT_VARS,
+    MACHINE_VAR,
+};
+#[cfg(feature = "serde")]
+use crate::baseline::{self, Regression};
+#[cfg(feature = "serde")]
+use crate::google_benchmark;
+use crate::{binary, metadata, stats, CycleStats};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::Path,
+    sync::atomic::Ordering,
+};
+
+impl CyclesPerByte {
+    /// Returns the number of regions flagged by [`CyclesPerByte::detect_interrupts`].
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub fn suspect_samples(&self) -> u64 {
+        self.interrupts
+            .as_ref()
+            .map_or(0, |check| check.suspect.load(Ordering::Relaxed))
+    }
+
+    /// Returns the cycles per event counted by [`CyclesPerByte::with_pmu_event`] over the regions
+    /// of the current benchmark so far, or `None` if no event was counted.
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub fn cycles_per_event(&self) -> Option<f64> {
+        self.pmu.as_ref()?.cycles_per_event()
+    }
+
+    /// Returns the effective frequency at the end of the run relative to its beginning, as
+    /// checked by [`CyclesPerByte::detect_throttling`]: below 1 if the core slowed down.
+    ///
+    /// Returns `None` if the check is disabled or fewer than two regions were measured.
+    #[cfg(all(feature = "msr", target_os = "linux"))]
+    pub fn frequency_drift_ratio(&self) -> Option<f64> {
+        self.throttling.as_ref()?.state.lock().unwrap().drift()
+    }
+
+    /// Returns the rate of the counter relative to `CLOCK_MONOTONIC_RAW` at the end of the run
+    /// relative to its beginning, as checked by [`CyclesPerByte::detect_tsc_drift`]: 1 if it kept
+    /// pace with real time, below 1 if it slowed down.
+    ///
+    /// Returns `None` if the check is disabled or fewer than three regions were sampled.
+    #[cfg(target_os = "linux")]
+    pub fn tsc_time_drift(&self) -> Option<f64> {
+        self.tsc_drift.as_ref()?.state.lock().unwrap().drift()
+    }
+
+    /// Returns whether any region ran on a different core than the first one, if enabled with
+    /// [`CyclesPerByte::detect_migration`].
+    pub fn migrated(&self) -> bool {
+        self.migration
+            .as_ref()
+            .is_some_and(|m| m.migrated.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of samples discarded since the last check, if enabled with
+    /// [`CyclesPerByte::max_discard_fraction`].
+    pub fn discarded_samples(&self) -> u64 {
+        self.discards
+            .as_ref()
+            .map_or(0, |discards| discards.discarded.load(Ordering::Relaxed))
+    }
+
+    /// Checks the samples measured since the previous check against
+    /// [`CyclesPerByte::max_discard_fraction`], and starts counting afresh.
+    ///
+    /// Returns the first benchmark criterion reported over the limit since the previous check, if
+    /// any, and otherwise the samples measured since the last report.
+    pub fn check_discards(&self) -> Result<(), Discarded> {
+        match self.discards {
+            Some(ref discards) => {
+                let exceeded = discards.exceeded.lock().unwrap().take();
+                match exceeded {
+                    Some(exceeded) => {
+                        discards.check().ok();
+                        Err(exceeded)
+                    }
+                    None => discards.check(),
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether the regions measured so far took more cycles than the budget set with
+    /// [`CyclesPerByte::cycle_budget`], shared with clones. Always `false` without a budget.
+    pub fn budget_exceeded(&self) -> bool {
+        self.budget
+            .as_ref()
+            .is_some_and(|budget| budget.spent.load(Ordering::Relaxed) > budget.limit)
+    }
+
+    /// Returns the statistics of the deltas measured so far, or all zeros if streaming statistics
+    /// are disabled.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// // Regions of 1 to 10000 cycles, each exactly once, in a scrambled order.
+    /// let region = AtomicU64::new(0);
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || {
+    ///         let i = region.fetch_add(1, Ordering::Relaxed);
+    ///         if i % 2 == 0 { 0 } else { (i / 2) * 7919 % 10_000 + 1 }
+    ///     })
+    ///     .streaming_stats();
+    ///
+    /// for _ in 0..10_000 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// let stats = measurement.stats();
+    /// assert_eq!((stats.count, stats.min, stats.max), (10_000, 1, 10_000));
+    /// assert_eq!(stats.mean, 5000.5);
+    /// assert!((stats.p50 - 5000.0).abs() < 50.0, "{}", stats.p50);
+    /// assert!((stats.p90 - 9000.0).abs() < 50.0, "{}", stats.p90);
+    /// assert!((stats.p99 - 9900.0).abs() < 50.0, "{}", stats.p99);
+    /// ```
+    pub fn stats(&self) -> CycleStats {
+        self.stats
+            .as_ref()
+            .map_or_else(CycleStats::default, |stats| stats.lock().unwrap().stats())
+    }
+
+    /// Returns the estimated 99th percentile of the deltas measured so far divided by `bytes`, or
+    /// `None` if streaming statistics are disabled, nothing was measured or `bytes` is 0.
+    ///
+    /// criterion never sees this number, so report it next to criterion's mean, or add it to
+    /// criterion's output with [`CyclesPerByte::p99_annotation`]. As with
+    /// [`CyclesPerByte::median_cpb`], each delta covers one region including warm-up.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// // Regions of 1 to 10000 cycles over 100 bytes, each exactly once, in a scrambled order.
+    /// let region = AtomicU64::new(0);
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || {
+    ///         let i = region.fetch_add(1, Ordering::Relaxed);
+    ///         if i % 2 == 0 { 0 } else { (i / 2) * 7919 % 10_000 + 1 }
+    ///     })
+    ///     .streaming_stats();
+    ///
+    /// assert_eq!(measurement.p99_cpb(100), None);
+    /// for _ in 0..10_000 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// let p99 = measurement.p99_cpb(100).unwrap();
+    /// assert!((p99 - 99.0).abs() < 0.5, "{}", p99);
+    /// assert_eq!(measurement.p99_cpb(0), None);
+    /// assert_eq!(CyclesPerByte::new().p99_cpb(100), None);
+    /// ```
+    pub fn p99_cpb(&self, bytes: u64) -> Option<f64> {
+        let stats = self.stats.as_ref()?.lock().unwrap().stats();
+        if bytes == 0 || stats.count == 0 {
+            return None;
+        }
+        Some(stats.p99 / bytes as f64)
+    }
+
+    /// Returns the recorded deltas, or an empty vector if recording is disabled.
+    pub fn samples(&self) -> Vec<u64> {
+        self.timeline()
+            .into_iter()
+            .map(|(_, delta)| delta)
+            .collect()
+    }
+
+    /// Writes the recorded deltas to `path` in the binary format described in
+    /// [`read_binary`](crate::read_binary), which loads them back.
+    ///
+    /// The file is little-endian on every machine and takes 8 bytes per sample, so millions of
+    /// samples are written and read far faster than as text. The header's counter frequency is
+    /// [`CyclesPerByte::resolved_frequency_hz`], or 0 (unknown) if that is `None`. Call this after
+    /// the benchmarks, not while they run: it copies the samples and writes the file on the
+    /// calling thread.
+    pub fn dump_binary<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let frequency = self.resolved_frequency_hz().unwrap_or(0);
+        binary::write(
+            path.as_ref(),
+            frequency,
+            self.provenance().as_deref(),
+            self.machine_id().as_deref(),
+            &self.samples(),
+        )
+    }
+
+    /// Returns the commit to export, see [`CyclesPerByte::with_provenance`].
+    fn provenance(&self) -> Option<String> {
+        let commit = match self.provenance {
+            Some(ref commit) => commit.clone(),
+            None => COMMIT_VARS.iter().find_map(|var| std::env::var(var).ok())?,
+        };
+        Some(commit).filter(|commit| !commit.is_empty())
+    }
+
+    /// Returns the machine to export, see [`CyclesPerByte::with_machine_id`].
+    fn machine_id(&self) -> Option<String> {
+        let machine = match self.machine {
+            Some(ref machine) => machine.clone(),
+            None => std::env::var(MACHINE_VAR).ok()?,
+        };
+        Some(machine).filter(|machine| !machine.is_empty())
+    }
+
+    /// Returns the frequency of the counter in Hz, from the source selected with
+    /// [`CyclesPerByte::frequency_source`].
+    ///
+    /// This is the TSC frequency, so it applies to the `rdtsc` backends; for the others it is only
+    /// known if given with [`FrequencySource::Fixed`]. Returns `None` if the source cannot tell.
+    /// The value is resolved on the first call and shared with clones, so a calibration only runs
+    /// once, on the calling thread.
+    pub fn resolved_frequency_hz(&self) -> Option<u64> {
+        if let FrequencySource::Fixed(hz) = self.frequency {
+            return Some(hz);
+        }
+        match self.counter {
+            Source::Rdtsc | Source::Serialized => *self
+                .resolved_frequency
+                .get_or_init(|| self.frequency.resolve()),
+            _ => None,
+        }
+    }
+
+    /// Returns the most frequent recorded delta, or `None` if nothing was recorded.
+    ///
+    /// For constant-time code this is usually the clean cost of a region, the value most samples
+    /// take before interrupts and cache misses inflate some of them. The deltas are first counted
+    /// into at most 4096 buckets of equal width spanning the recorded range, which is a bucket
+    /// per cycle for ranges of up to 4096 cycles. The result is the most frequent exact delta
+    /// within the fullest bucket, so it is always a recorded value, but with wider buckets a
+    /// sharp peak can lose to a broad plateau that collects more samples per bucket. Ties go to
+    /// the smaller value. Each entry covers a whole batch of criterion iterations, so this is most
+    /// meaningful for deltas from [`CyclesPerByte::measure`] or with a fixed iteration count.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// // Mostly 100 cycles, sometimes a cycle more and some interrupted regions far above.
+    /// let mut deltas = vec![100; 20];
+    /// deltas.extend([101; 18]);
+    /// deltas.extend([5000; 25]);
+    /// deltas.extend((0..20).map(|i| 20_000 + i * 997));
+    /// let readings = Arc::new(Mutex::new(deltas.iter().flat_map(|&d| [0, d]).collect::<Vec<_>>()));
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || readings.lock().unwrap().remove(0))
+    ///     .record_samples();
+    ///
+    /// assert_eq!(measurement.mode_cycles(), None);
+    /// for _ in &deltas {
+    ///     measurement.measure(|| ());
+    /// }
+    /// // The 5000s outnumber either of 100 and 101, but not both in one 10-cycle bucket.
+    /// assert_eq!(measurement.mode_cycles(), Some(100));
+    /// ```
+    pub fn mode_cycles(&self) -> Option<u64> {
+        stats::mode(&self.samples())
+    }
+
+    /// Returns the median of the recorded deltas divided by `bytes`, or `None` if nothing was
+    /// recorded or `bytes` is 0.
+    ///
+    /// Unlike criterion's mean, the median ignores a few interrupted samples; criterion never
+    /// prints it, so report it separately. Each delta covers one region, so this is per iteration
+    /// only with [`CyclesPerByte::measure`] or criterion's `BatchSize::PerIteration`.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// // Regions of 640 to 660 cycles over 64 bytes, and one interrupted region.
+    /// let deltas = [640, 650, 660, 645, 655, 50_000];
+    /// let readings = Arc::new(Mutex::new(deltas.iter().flat_map(|&d| [0, d]).collect::<Vec<_>>()));
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || readings.lock().unwrap().remove(0))
+    ///     .record_samples();
+    ///
+    /// assert_eq!(measurement.median_cpb(64), None);
+    /// for _ in &deltas {
+    ///     measurement.measure(|| ());
+    /// }
+    /// let mean = measurement.samples().iter().sum::<u64>() as f64 / 6.0 / 64.0;
+    /// assert!(mean > 130.0);
+    /// assert_eq!(measurement.median_cpb(64), Some(652.5 / 64.0));
+    /// assert_eq!(measurement.median_cpb(0), None);
+    /// ```
+    pub fn median_cpb(&self, bytes: u64) -> Option<f64> {
+        if bytes == 0 {
+            return None;
+        }
+        Some(stats::median(&self.samples())? / bytes as f64)
+    }
+
+    /// Returns the deltas recorded while the id set with [`metadata::set_id`] was `id`, in the
+    /// order they were measured, or an empty vector if there are none.
+    pub fn samples_with_id(&self, id: &str) -> Vec<u64> {
+        self.samples.as_ref().map_or_else(Vec::new, |samples| {
+            samples
+                .lock()
+                .unwrap()
+                .by_id
+                .get(id)
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    /// Tests whether the deltas recorded under the ids `a` and `b` have the same mean, and
+    /// returns the p-value as the error if they differ at significance level `alpha`.
+    ///
+    /// This is Welch's two-sided t-test, as a timing-leak check for constant-time code: measure the
+    /// same operation on two classes of inputs under two ids set with [`metadata::set_id`],
+    /// interleaving them so that drift affects both alike. A pass is not proof of constant time,
+    /// and with enough samples a failure may be a fraction of a cycle.
+    ///
+    /// # Panics
+    ///
+    /// If either id has fewer than two recorded deltas, e.g. because recording is disabled.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// /// Records `deltas` under `id`, one `measure` call each.
+    /// fn measure(measurement: &CyclesPerByte, readings: &Mutex<Vec<u64>>, id: &str, deltas: &[u64]) {
+    ///     metadata::set_id(id);
+    ///     for &delta in deltas {
+    ///         readings.lock().unwrap().extend([0, delta]);
+    ///         measurement.measure(|| ());
+    ///     }
+    ///     metadata::clear();
+    /// }
+    ///
+    /// let readings = Arc::new(Mutex::new(Vec::new()));
+    /// let counter = readings.clone();
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || counter.lock().unwrap().remove(0))
+    ///     .record_samples();
+    ///
+    /// // Noise in the same range, in a different order.
+    /// let noise: Vec<u64> = (0..200).map(|i| 1000 + (i * 37) % 50).collect();
+    /// let shuffled: Vec<u64> = (0..200).map(|i| 1000 + (i * 13 + 7) % 50).collect();
+    /// measure(&measurement, &readings, "zeros", &noise);
+    /// measure(&measurement, &readings, "random", &shuffled);
+    /// assert_eq!(measurement.samples_with_id("zeros").len(), 200);
+    /// assert_eq!(measurement.assert_indistinguishable("zeros", "random", 0.01), Ok(()));
+    ///
+    /// // The same noise, 10 cycles slower.
+    /// let slower: Vec<u64> = noise.iter().map(|delta| delta + 10).collect();
+    /// measure(&measurement, &readings, "leaky", &slower);
+    /// let p = measurement.assert_indistinguishable("zeros", "leaky", 0.01).unwrap_err();
+    /// assert!(p < 1e-6);
+    ///
+    /// // t = -1.897 with 5.88 degrees of freedom.
+    /// measure(&measurement, &readings, "a", &[1, 2, 3, 4, 5]);
+    /// measure(&measurement, &readings, "b", &[2, 4, 6, 8, 10]);
+    /// let p = measurement.assert_indistinguishable("a", "b", 0.2).unwrap_err();
+    /// assert!((p - 0.10753).abs() < 1e-5);
+    /// ```
+    pub fn assert_indistinguishable(&self, a: &str, b: &str, alpha: f64) -> Result<(), f64> {
+        let (a_samples, b_samples) = (self.samples_with_id(a), self.samples_with_id(b));
+        for (id, samples) in [(a, &a_samples), (b, &b_samples)] {
+            assert!(
+                samples.len() >= 2,
+                "criterion-cycles-per-byte: {} deltas recorded under {:?}, but the t-test needs at least two per id to estimate a variance; is record_samples enabled?",
+                samples.len(),
+                id
+            );
+        }
+        let p = stats::welch_p_value(&a_samples, &b_samples)
+            .expect("both ids have at least two deltas");
+        if p < alpha {
+            Err(p)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the recorded `(start, delta)` pairs in the order they were measured, or an empty
+    /// vector if recording is disabled.
+    ///
+    /// `start` is the raw counter value at the beginning of the region, so gaps between
+    /// `start + delta` of one entry and `start` of the next show time spent outside the measured
+    /// regions, e.g. in criterion's own bookkeeping or while the thread was descheduled. Counter
+    /// values are only comparable within one core unless the TSC is invariant and synchronized.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    ///
+    /// let measurement = CyclesPerByte::new().record_samples();
+    /// let first = measurement.measure(|| ());
+    /// let second = measurement.measure(|| ());
+    ///
+    /// let timeline = measurement.timeline();
+    /// assert_eq!((timeline[0].1, timeline[1].1), (first, second));
+    /// ```
+    pub fn timeline(&self) -> Vec<(u64, u64)> {
+        match self.samples {
+            Some(ref samples) => samples.lock().unwrap().timeline.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the cpb of each benchmark id seen so far, see [`CyclesPerByte::track_cpb`], or an
+    /// empty map if tracking is disabled.
+    pub fn cpb_by_id(&self) -> BTreeMap<String, f64> {
+        self.tracked
+            .as_ref()
+            .map_or_else(BTreeMap::new, |tracked| tracked.cpb())
+    }
+
+    /// Summarizes a size sweep tracked with [`CyclesPerByte::track_cpb`] into the mean cpb
+    /// weighted by `weights`, the weight of each size in bytes.
+    ///
+    /// The size of an id is the one its last part names, see
+    /// [`infer_bytes_from_id`](crate::metadata::infer_bytes_from_id), or else the byte count of its
+    /// cpb. Ids and sizes without a match in the other are left out, and every tracked id counts,
+    /// so give each sweep its own measurement. Returns NaN if no id has a weight or the weights
+    /// sum to 0.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "criterion")]
+    /// # {
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+    /// use std::collections::HashMap;
+    ///
+    /// let measurement = CyclesPerByte::new().track_cpb();
+    /// let formatter = measurement.formatter();
+    /// for (id, bytes, cycles) in [("xor/16", 16, 128.0), ("xor/4096", 4096, 2048.0)] {
+    ///     metadata::set_id(id);
+    ///     formatter.format_throughput(&Throughput::Bytes(bytes), cycles);
+    /// }
+    /// // An id without a size, registered with metadata instead.
+    /// metadata::set_id("xor/page");
+    /// metadata::set_bytes_and_elements(65536, 0, "");
+    /// formatter.format_throughput(&Throughput::Bytes(1), 16384.0);
+    /// metadata::clear();
+    ///
+    /// // 8 cpb at 16 bytes, 0.5 cpb at 4096 and 0.25 cpb at 65536.
+    /// let weights: HashMap<u64, f64> = [(16, 3.0), (4096, 1.0), (1 << 30, 5.0)].into();
+    /// assert_eq!(measurement.weighted_cpb(&weights), (3.0 * 8.0 + 0.5) / 4.0);
+    /// let weights: HashMap<u64, f64> = [(16, 3.0), (4096, 1.0), (65536, 4.0)].into();
+    /// assert_eq!(measurement.weighted_cpb(&weights), (3.0 * 8.0 + 0.5 + 4.0 * 0.25) / 8.0);
+    /// assert!(measurement.weighted_cpb(&HashMap::new()).is_nan());
+    /// # }
+    /// ```
+    pub fn weighted_cpb(&self, weights: &HashMap<u64, f64>) -> f64 {
+        let tracked = match self.tracked {
+            Some(ref tracked) => tracked,
+            None => return f64::NAN,
+        };
+        let bytes = tracked.bytes.lock().unwrap().clone();
+        let (mut sum, mut total) = (0.0, 0.0);
+        for (id, cpb) in tracked.cpb() {
+            let size = metadata::infer_bytes_from_id(&id).or_else(|| bytes.get(&id).copied());
+            if let Some(&weight) = size.and_then(|size| weights.get(&size)) {
+                sum += weight * cpb;
+                total += weight;
+            }
+        }
+        if total == 0.0 {
+            return f64::NAN;
+        }
+        sum / total
+    }
+
+    /// Writes the cpb of each benchmark id to `path` as JSON, in the format described in
+    /// [`CyclesPerByte::check_baseline`].
+    ///
+    /// Call it after the benchmarks, e.g. at the end of a custom `main` that runs the criterion
+    /// groups, and commit the file to compare later runs against it. The file also records the
+    /// commit of [`CyclesPerByte::with_provenance`], if any.
+    #[cfg(feature = "serde")]
+    pub fn save_baseline<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        baseline::write(path.as_ref(), self.provenance(), self.cpb_by_id())
+    }
+
+    /// Writes the results of each benchmark id to `path` in the JSON format of google/benchmark,
+    /// replacing the file, for dashboards and scripts that read `--benchmark_format=json` output.
+    ///
+    /// Each id is a run in the `benchmarks` array, named after the id, with one repetition on one
+    /// thread. The cycles per iteration and the cpb are the user counters `cycles` and `cpb`,
+    /// which google/benchmark puts next to the other fields of a run, and `cpb` is left out for
+    /// ids without a byte count. `real_time` and `cpu_time` both hold the cycles converted to
+    /// nanoseconds at [`CyclesPerByte::resolved_frequency_hz`], and are 0 if it is unknown:
+    ///
+    /// ```json
+    /// { "name": "xor/4096", "run_name": "xor/4096", "run_type": "iteration", "repetitions": 1,
+    ///   "repetition_index": 0, "threads": 1, "iterations": 100, "real_time": 1024.0,
+    ///   "cpu_time": 1024.0, "time_unit": "ns", "cycles": 2048.0, "cpb": 0.5 }
+    /// ```
+    ///
+    /// The ids are those of [`CyclesPerByte::track_cpb`] and of [`CyclesPerByte::record_samples`],
+    /// sorted. A tracked id reports criterion's estimate; one that was only recorded reports the
+    /// median of its samples and, as `iterations`, their number. The `context` also holds the
+    /// commit of [`CyclesPerByte::with_provenance`], under a key google/benchmark's readers ignore.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::{metadata, CyclesPerByte, FrequencySource};
+    ///
+    /// let measurement = CyclesPerByte::new()
+    ///     .track_cpb()
+    ///     .record_samples()
+    ///     .frequency_source(FrequencySource::Fixed(2_000_000_000))
+    ///     .with_provenance("5f3c2a1")
+    ///     .with_machine_id("bench-03");
+    /// metadata::set_id("xor/4096");
+    /// measurement
+    ///     .formatter()
+    ///     .format_throughput(&Throughput::Bytes(4096), 2048.0);
+    /// metadata::set_id("keygen");
+    /// measurement.measure(|| ());
+    /// metadata::clear();
+    ///
+    /// let path = std::env::temp_dir().join(format!("cpb-gbench-{}.json", std::process::id()));
+    /// measurement.save_google_benchmark(&path)?;
+    /// let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    /// std::fs::remove_file(&path)?;
+    ///
+    /// assert_eq!(json["context"]["host_name"], "bench-03");
+    /// assert_eq!(json["context"]["mhz_per_cpu"], 2000);
+    /// assert_eq!(json["context"]["commit"], "5f3c2a1");
+    /// let benchmarks = json["benchmarks"].as_array().unwrap();
+    /// assert_eq!(benchmarks.len(), 2);
+    /// assert_eq!(benchmarks[0]["name"], "keygen");
+    /// assert_eq!(benchmarks[0]["iterations"], 1);
+    /// assert!(benchmarks[0].get("cpb").is_none());
+    /// let xor = &benchmarks[1];
+    /// assert_eq!(xor["name"], "xor/4096");
+    /// assert_eq!(xor["run_type"], "iteration");
+    /// assert_eq!(xor["cycles"], 2048.0);
+    /// assert_eq!(xor["cpb"], 0.5);
+    /// assert_eq!(xor["real_time"], 1024.0);
+    /// assert_eq!(xor["time_unit"], "ns");
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn save_google_benchmark<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let cpb = self.cpb_by_id();
+        let bytes = self.tracked.as_ref().map_or_else(BTreeMap::new, |tracked| {
+            tracked.bytes.lock().unwrap().clone()
+        });
+        let recorded = self.samples.as_ref().map_or_else(BTreeMap::new, |samples| {
+            samples.lock().unwrap().by_id.clone()
+        });
+        let mut ids: Vec<&String> = cpb.keys().chain(recorded.keys()).collect();
+        ids.sort();
+        ids.dedup();
+        let hz = self.resolved_frequency_hz();
+        let benchmarks = ids
+            .into_iter()
+            .filter_map(|id| {
+                let deltas = recorded.get(id);
+                let (cycles, cpb) = match (cpb.get(id), bytes.get(id)) {
+                    (Some(&cpb), Some(&bytes)) => (cpb * bytes as f64, Some(cpb)),
+                    _ => {
+                        let cycles = stats::median(deltas?)?;
+                        let cpb = metadata::infer_bytes_from_id(id).map(|b| cycles / b as f64);
+                        (cycles, cpb)
+                    }
+                };
+                let iterations = deltas.map_or(1, |deltas| deltas.len() as u64);
+                Some(google_benchmark::Run::new(
+                    id.clone(),
+                    iterations,
+                    cycles,
+                    cpb,
+                    hz,
+                ))
+            })
+            .collect();
+        google_benchmark::write(
+            path.as_ref(),
+            self.provenance(),
+            self.machine_id(),
+            hz,
+            benchmarks,
+        )
+    }
+
+    /// Compares the cpb of each benchmark id with the baseline saved at `path`, and returns the
+    /// ids that got slower by more than the fraction `tolerance`, e.g. 0.05 for 5%, or none if
+    /// nothing did.
+    ///
+    /// Ids only in the baseline or only in this run are not compared, so adding or removing a
+    /// benchmark does not fail the check. A baseline is an object with the format version and the
+    /// cpb of each id, sorted by id, and the commit it was measured at if known:
+    ///
+    /// ```json
+    /// { "version": 1, "commit": "5f3c2a1", "cpb": { "xor/16": 8.0, "xor/4096": 0.5 } }
+    /// ```
+    ///
+    /// Fails if `path` cannot be read, e.g. with [`io::ErrorKind::NotFound`] on the first run, or
+    /// with [`io::ErrorKind::InvalidData`] if it is not a baseline.
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+    ///
+    /// fn run(cpb_16: f64, cpb_4096: f64) -> CyclesPerByte {
+    ///     let measurement = CyclesPerByte::new().track_cpb().with_provenance("5f3c2a1");
+    ///     for (size, cpb) in [(16, cpb_16), (4096, cpb_4096)] {
+    ///         metadata::set_id(format!("xor/{}", size));
+    ///         measurement
+    ///             .formatter()
+    ///             .format_throughput(&Throughput::Bytes(size), cpb * size as f64);
+    ///     }
+    ///     metadata::clear();
+    ///     measurement
+    /// }
+    ///
+    /// let path = std::env::temp_dir().join(format!("cpb-baseline-{}.json", std::process::id()));
+    /// let first = run(8.0, 0.5).check_baseline(&path, 0.05);
+    /// assert_eq!(first.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    /// run(8.0, 0.5).save_baseline(&path)?;
+    /// assert!(std::fs::read_to_string(&path)?.contains(r#""commit": "5f3c2a1""#));
+    ///
+    /// assert!(run(8.2, 0.4).check_baseline(&path, 0.05)?.is_empty());
+    /// let regressions = run(9.0, 0.5).check_baseline(&path, 0.05)?;
+    /// assert_eq!(regressions.len(), 1);
+    /// assert_eq!(regressions[0].id, "xor/16");
+    /// assert_eq!((regressions[0].baseline, regressions[0].current), (8.0, 9.0));
+    /// assert_eq!(regressions[0].ratio(), 1.125);
+    /// std::fs::remove_file(&path)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn check_baseline<P: AsRef<Path>>(
+        &self,
+        path: P,
+        tolerance: f64,
+    ) -> io::Result<Vec<Regression>> {
+        let saved = baseline::read(path.as_ref())?;
+        Ok(baseline::compare(&saved, &self.cpb_by_id(), tolerance))
+    }
+
+    /// Returns the sum of all recorded deltas, or zero if recording is disabled.
+    ///
+    /// The sum is accumulated as a `u128`, so it stays exact for runs totalling more than 2^64
+    /// cycles. criterion's own statistics are unaffected by this: it converts each sample to
+    /// `f64` and computes its mean from those.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesPerByte;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    ///
+    /// // Every region spans the whole u64 range.
+    /// let end = AtomicBool::new(false);
+    /// let measurement = CyclesPerByte::new()
+    ///     .with_counter(move || if end.fetch_xor(true, Ordering::Relaxed) { u64::MAX } else { 0 })
+    ///     .record_samples();
+    ///
+    /// for _ in 0..1000 {
+    ///     measurement.measure(|| ());
+    /// }
+    /// assert_eq!(measurement.total_cycles(), 1000 * u128::from(u64::MAX));
+    /// ```
+    pub fn total_cycles(&self) -> u128 {
+        self.samples
+            .as_ref()
+            .map_or(0, |samples| samples.lock().unwrap().total)
+    }
+
+    /// Returns the average cycles of the setup regions marked with
+    /// [`tic_setup`](crate::tic_setup) and [`toc_setup`](crate::toc_setup) so far, or `None` if
+    /// there were none.
+    pub fn setup_cycles(&self) -> Option<f64> {
+        self.setup.average()
+    }
+
+    /// Returns the total cycles between consecutive [`mark`](crate::mark)s of each pair of labels,
+    /// as `"from -> to"`, in the order the pairs were first seen.
+    ///
+    /// Like the setup regions, the phases accumulate over all samples including warm-up, and over
+    /// every benchmark measured with the measurement and its clones, until
+    /// [`CyclesPerByte::clear_samples`] resets them.
+    pub fn phase_report(&self) -> Vec<(String, u64)> {
+        self.phases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(from, to, cycles)| (format!("{} -> {}", from, to), *cycles))
+            .collect()
+    }
+
+    /// Discards all recorded samples, and resets the streaming statistics, setup regions and
+    /// phases.
+    pub fn clear_samples(&self) {
+        self.setup.cycles.store(0, Ordering::Relaxed);
+        self.setup.regions.store(0, Ordering::Relaxed);
+        self.phases.lock().unwrap().clear();
+        if let Some(ref samples) = self.samples {
+            *samples.lock().unwrap() = Recording::default();
+        }
+        if let Some(ref stats) = self.stats {
+            *stats.lock().unwrap() = stats::Streaming::new();
+        }
+    }
+
+    /// Returns the counter that is actually read, after any fallback.
+    pub fn active_backend(&self) -> Backend {
+        match self.counter {
+            Source::Rdtsc => Backend::Rdtsc,
+            Source::Serialized => Backend::Serialized,
+            #[cfg(all(feature = "msr", target_os = "linux"))]
+            Source::FixedCounter(_) => Backend::FixedCounter,
+            #[cfg(all(feature = "rdpmc", target_os = "linux"))]
+            Source::Rdpmc => Backend::Rdpmc,
+            Source::Custom(_) => Backend::Custom,
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! The state shared between the clones of a measurement by the options that need any.
+
+use super::Discarded;
+#[cfg(all(feature = "msr", target_os = "linux"))]
+use crate::msr;
+use crate::{metadata, tsc_aux};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Recorded `(start, delta)` pairs, the sum of all deltas, and the deltas measured under each
+/// benchmark id.
+#[derive(Default)]
+pub(super) struct Recording {
+    pub(super) timeline: Vec<(u64, u64)>,
+    pub(super) total: u128,
+    pub(super) by_id: BTreeMap<String, Vec<u64>>,
+}
+
+impl Recording {
+    pub(super) fn push(&mut self, start: u64, delta: u64) {
+        self.timeline.push((start, delta));
+        self.total += u128::from(delta);
+        metadata::with_id(|id| {
+            if let Some(id) = id {
+                match self.by_id.get_mut(id) {
+                    Some(deltas) => deltas.push(delta),
+                    None => {
+                        self.by_id.insert(id.to_owned(), vec![delta]);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Ring 0 instruction counts around each region, to flag regions the kernel ran in.
+#[cfg(all(feature = "msr", target_os = "linux"))]
+pub(super) struct InterruptCheck {
+    /// Reads the ring 0 instructions retired.
+    pub(super) counter: Box<dyn Fn() -> u64 + Send + Sync>,
+    /// The most ring 0 instructions a region may see without counting as interrupted.
+    pub(super) threshold: u64,
+    pub(super) start: AtomicU64,
+    pub(super) suspect: AtomicU64,
+}
+
+/// The programmed event counts and cycles of the benchmark being measured, for `cycles/event`.
+#[cfg(all(feature = "msr", target_os = "linux"))]
+pub(super) struct PmuEvent {
+    /// Reads the count of the programmed event.
+    pub(super) counter: Box<dyn Fn() -> u64 + Send + Sync>,
+    pub(super) start: AtomicU64,
+    pub(super) cycles: AtomicU64,
+    pub(super) events: AtomicU64,
+    /// Set once the ratio has been formatted, so that the next region starts new totals.
+    pub(super) reported: AtomicBool,
+}
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+impl PmuEvent {
+    pub(super) fn add(&self, cycles: u64, events: u64) {
+        if self.reported.swap(false, Ordering::Relaxed) {
+            self.cycles.store(0, Ordering::Relaxed);
+            self.events.store(0, Ordering::Relaxed);
+        }
+        self.cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.events.fetch_add(events, Ordering::Relaxed);
+    }
+
+    pub(super) fn cycles_per_event(&self) -> Option<f64> {
+        let events = self.events.load(Ordering::Relaxed);
+        if events == 0 {
+            return None;
+        }
+        Some(self.cycles.load(Ordering::Relaxed) as f64 / events as f64)
+    }
+}
+
+/// The effective frequency of the core early and late in the run, from `IA32_APERF` and
+/// `IA32_MPERF`.
+#[cfg(all(feature = "msr", target_os = "linux"))]
+pub(super) struct Throttling {
+    pub(super) clocks: msr::PerformanceClocks,
+    /// The largest relative change of the effective frequency that goes without a warning.
+    pub(super) threshold: f64,
+    pub(super) state: Mutex<ThrottlingState>,
+}
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+pub(super) struct ThrottlingState {
+    /// `(APERF, MPERF)` at the end of the previous region, or when the check was enabled.
+    pub(super) previous: (u64, u64),
+    /// `APERF / MPERF` from enabling the check to the end of the first region.
+    pub(super) first: Option<f64>,
+    /// `APERF / MPERF` over the last region and the time before it since the previous one.
+    pub(super) last: Option<f64>,
+}
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+impl Throttling {
+    pub(super) fn sample(&self) {
+        let now = self.clocks.read();
+        let mut state = self.state.lock().unwrap();
+        let mperf = now.1.wrapping_sub(state.previous.1);
+        if mperf != 0 {
+            let ratio = now.0.wrapping_sub(state.previous.0) as f64 / mperf as f64;
+            if state.first.is_none() {
+                state.first = Some(ratio);
+            } else {
+                state.last = Some(ratio);
+            }
+        }
+        state.previous = now;
+    }
+}
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+impl ThrottlingState {
+    pub(super) fn drift(&self) -> Option<f64> {
+        Some(self.last? / self.first?)
+    }
+}
+
+#[cfg(all(feature = "msr", target_os = "linux"))]
+impl Drop for Throttling {
+    fn drop(&mut self) {
+        let drift = match self.state.get_mut() {
+            Ok(state) => state.drift(),
+            Err(_) => None,
+        };
+        if let Some(drift) = drift.filter(|drift| (drift - 1.0).abs() > self.threshold) {
+            eprintln!(
+                "criterion-cycles-per-byte: the core ran at {:.1}% of its initial frequency at the end of the run, results are not comparable",
+                drift * 100.0
+            );
+        }
+    }
+}
+
+/// The rate of the counter relative to `CLOCK_MONOTONIC_RAW` early and late in the run.
+#[cfg(target_os = "linux")]
+pub(super) struct TscDrift {
+    /// The largest relative change of the rate that goes without a warning.
+    pub(super) tolerance: f64,
+    /// The regions ended so far, of which every `TscDrift::EVERY`th is sampled.
+    pub(super) regions: AtomicU64,
+    pub(super) state: Mutex<TscDriftState>,
+}
+
+#[cfg(target_os = "linux")]
+pub(super) struct TscDriftState {
+    /// The counter and the clock in nanoseconds at the previous sampled region.
+    pub(super) previous: Option<(u64, u64)>,
+    /// Counter ticks per nanosecond between the first two sampled regions.
+    pub(super) first: Option<f64>,
+    /// Counter ticks per nanosecond between the last two sampled regions.
+    pub(super) last: Option<f64>,
+}
+
+#[cfg(target_os = "linux")]
+impl TscDrift {
+    pub(super) const EVERY: u64 = 8;
+
+    /// Compares the counter value `end` with the clock, if this region is sampled.
+    pub(super) fn sample(&self, end: u64) {
+        self.sample_at(end, monotonic_raw_ns);
+    }
+
+    /// Compares the counter value `end` with the nanoseconds read by `clock`, if this region is
+    /// sampled.
+    pub(super) fn sample_at(&self, end: u64, clock: impl FnOnce() -> u64) {
+        if self.regions.fetch_add(1, Ordering::Relaxed) % Self::EVERY != 0 {
+            return;
+        }
+        let now = (end, clock());
+        let mut state = self.state.lock().unwrap();
+        if let Some(previous) = state.previous {
+            let nanoseconds = now.1.saturating_sub(previous.1);
+            if nanoseconds != 0 {
+                let ratio = now.0.wrapping_sub(previous.0) as f64 / nanoseconds as f64;
+                if state.first.is_none() {
+                    state.first = Some(ratio);
+                } else {
+                    state.last = Some(ratio);
+                }
+            }
+        }
+        state.previous = Some(now);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TscDriftState {
+    pub(super) fn drift(&self) -> Option<f64> {
+        Some(self.last? / self.first?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for TscDrift {
+    fn drop(&mut self) {
+        let drift = match self.state.get_mut() {
+            Ok(state) => state.drift(),
+            Err(_) => None,
+        };
+        if let Some(drift) = drift.filter(|drift| (drift - 1.0).abs() > self.tolerance) {
+            eprintln!(
+                "criterion-cycles-per-byte: the counter ran at {:.1}% of its initial rate relative to CLOCK_MONOTONIC_RAW at the end of the run, results are not comparable",
+                drift * 100.0
+            );
+        }
+    }
+}
+
+/// Reads `CLOCK_MONOTONIC_RAW`, which is not slewed by NTP, in nanoseconds.
+#[cfg(target_os = "linux")]
+pub(super) fn monotonic_raw_ns() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// The `TSC_AUX` value seen by the first region, whether any later one saw another, and the value
+/// at the start of the current region.
+pub(super) struct Migration {
+    pub(super) first: AtomicU64,
+    pub(super) migrated: AtomicBool,
+    pub(super) region: AtomicU64,
+}
+
+impl Migration {
+    pub(super) const UNSET: u64 = u64::MAX;
+
+    /// Reads `TSC_AUX`, flags a migration if it differs from the first value, and returns it.
+    #[inline]
+    pub(super) fn check(&self) -> Option<u64> {
+        let aux = u64::from(tsc_aux()?);
+        if let Err(first) =
+            self.first
+                .compare_exchange(Self::UNSET, aux, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            if first != aux {
+                self.migrated.store(true, Ordering::Relaxed);
+            }
+        }
+        Some(aux)
+    }
+}
+
+/// The cycles the measured regions may take in total, and how many they have taken.
+pub(super) struct Budget {
+    pub(super) limit: u64,
+    pub(super) spent: AtomicU64,
+}
+
+/// The cpb of each benchmark id, from the throughput results most recently formatted for it.
+#[derive(Default)]
+pub(super) struct Tracked {
+    pub(super) by_id: Mutex<BTreeMap<String, Vec<f64>>>,
+    /// The byte count of the most recent result of each id.
+    pub(super) bytes: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Tracked {
+    /// criterion formats the upper bound, point estimate and lower bound of each result, in an
+    /// order that differs between reports, so the last three values are kept and their median,
+    /// whatever the order, is the point estimate.
+    #[cfg(feature = "criterion")]
+    pub(super) const KEPT: usize = 3;
+
+    #[cfg(feature = "criterion")]
+    pub(super) fn push(&self, id: String, bytes: u64, cpb: f64) {
+        self.bytes.lock().unwrap().insert(id.clone(), bytes);
+        let mut by_id = self.by_id.lock().unwrap();
+        let recent = by_id.entry(id).or_default();
+        if recent.len() == Self::KEPT {
+            recent.remove(0);
+        }
+        recent.push(cpb);
+    }
+
+    pub(super) fn cpb(&self) -> BTreeMap<String, f64> {
+        self.by_id
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, recent)| {
+                let mut sorted = recent.clone();
+                sorted.sort_by(f64::total_cmp);
+                let mid = sorted.len() / 2;
+                let cpb = if sorted.len() % 2 == 0 {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                };
+                (id.clone(), cpb)
+            })
+            .collect()
+    }
+}
+
+/// The writer each delta is streamed to, buffered, and when it was last flushed.
+pub(super) struct Stream {
+    pub(super) writer: BufWriter<Box<dyn Write + Send>>,
+    pub(super) flushed: Instant,
+}
+
+impl Stream {
+    pub(super) fn new(writer: Box<dyn Write + Send>) -> Arc<Mutex<Stream>> {
+        Arc::new(Mutex::new(Stream {
+            writer: BufWriter::with_capacity(Stream::CAPACITY, writer),
+            flushed: Instant::now(),
+        }))
+    }
+
+    /// Returns a stream to `writer` if `value`, that of `CPB_STREAM`, is set to anything but `0`.
+    pub(super) fn from_env(
+        value: Option<OsString>,
+        writer: Box<dyn Write + Send>,
+    ) -> Option<Arc<Mutex<Stream>>> {
+        value
+            .filter(|v| !v.is_empty() && v != "0")
+            .map(|_| Stream::new(writer))
+    }
+
+    pub(super) const CAPACITY: usize = 8 * 1024;
+
+    /// The longest a line waits in the buffer, unless no region ends in the meantime.
+    pub(super) const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Buffers the line of a delta ending at counter value `end`, and writes the buffer out if it
+    /// is full or due. Write errors are ignored: monitoring output must not fail the benchmark.
+    pub(super) fn push(&mut self, delta: u64, end: u64) {
+        let _ = writeln!(self.writer, "{{\"delta\":{},\"ts\":{}}}", delta, end);
+        if self.flushed.elapsed() >= Stream::FLUSH_INTERVAL {
+            let _ = self.writer.flush();
+            self.flushed = Instant::now();
+        }
+    }
+}
+
+/// The cycles and number of the setup regions marked with [`tic_setup`] and [`toc_setup`].
+#[derive(Default)]
+pub(super) struct Setup {
+    pub(super) cycles: AtomicU64,
+    pub(super) regions: AtomicU64,
+}
+
+impl Setup {
+    pub(super) fn average(&self) -> Option<f64> {
+        let regions = self.regions.load(Ordering::Relaxed);
+        if regions == 0 {
+            return None;
+        }
+        Some(self.cycles.load(Ordering::Relaxed) as f64 / regions as f64)
+    }
+}
+
+/// Samples measured since the last [`CyclesPerByte::check_discards`], and how many of them were
+/// discarded.
+pub(super) struct Discards {
+    pub(super) max_fraction: f64,
+    pub(super) samples: AtomicU64,
+    pub(super) discarded: AtomicU64,
+    /// The first benchmark the formatter found over the limit.
+    pub(super) exceeded: Mutex<Option<Discarded>>,
+}
+
+impl Discards {
+    /// Checks the samples measured since the previous check, and starts counting afresh.
+    pub(super) fn check(&self) -> Result<(), Discarded> {
+        let samples = self.samples.swap(0, Ordering::Relaxed);
+        let discarded = self.discarded.swap(0, Ordering::Relaxed);
+        if samples != 0 && discarded as f64 / samples as f64 > self.max_fraction {
+            return Err(Discarded { discarded, samples });
+        }
+        Ok(())
+    }
+}
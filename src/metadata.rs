@@ -0,0 +1,197 @@
+//! Per-iteration counts that criterion's [`Throughput`] cannot express.
+//!
+//! criterion's [`Throughput`] holds a single value, so it cannot say that an iteration processes
+//! both a number of bytes and a number of elements, e.g. 16 packets totalling 4 KiB for an AEAD
+//! benchmark. The counts set here are kept per thread and picked up by the formatter when it
+//! reports the benchmark, which criterion does on the thread that ran it.
+//!
+//! Set the counts in the benchmark closure but outside of `iter`, so that the store is not part
+//! of the timed region. Keep setting a [`Throughput`] as well, since criterion only prints the
+//! throughput line when one is set; the counts here then take precedence over its value.
+//!
+//! The counts describe the current benchmark until they are replaced or [`clear`]ed. Nothing
+//! resets them between benchmarks, so clear them in benchmarks that follow one that set them,
+//! or they will be reported with the previous benchmark's counts.
+//!
+//! The same goes for the id set with [`set_id`], under which
+//! [`CyclesPerByte::track_cpb`](crate::CyclesPerByte::track_cpb) files the results, since the
+//! formatter is not told which benchmark it is formatting either.
+//!
+//! ```rust
+//! # #[cfg(feature = "criterion")]
+//! # {
+//! # fn seal(_: &[u8]) {}
+//! use criterion::{measurement::Measurement, Criterion, Throughput};
+//! use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+//!
+//! fn bench(c: &mut Criterion<CyclesPerByte>) {
+//!     let packets = vec![vec![0u8; 256]; 16];
+//!     let bytes = packets.iter().map(|p| p.len() as u64).sum();
+//!
+//!     let mut group = c.benchmark_group("aead");
+//!     group.throughput(Throughput::Bytes(bytes));
+//!     group.bench_function("seal", |b| {
+//!         metadata::set_bytes_and_elements(bytes, packets.len() as u64, "pkt");
+//!         b.iter(|| packets.iter().for_each(|p| seal(p)))
+//!     });
+//!     group.finish();
+//!     metadata::clear();
+//! }
+//!
+//! metadata::set_bytes_and_elements(4096, 16, "pkt");
+//! let measurement = CyclesPerByte::new();
+//! assert_eq!(
+//!     measurement.formatter().format_throughput(&Throughput::Bytes(4096), 8192.0),
+//!     "2.0000 cpb (512.0000 c/pkt)"
+//! );
+//! metadata::clear();
+//! # }
+//! ```
+//!
+//! [`Throughput`]: https://docs.rs/criterion/0.4/criterion/enum.Throughput.html
+
+use std::cell::{Cell, RefCell};
+
+/// What one iteration of the current benchmark processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// Bytes processed per iteration.
+    pub bytes: u64,
+    /// Elements processed per iteration.
+    pub elements: u64,
+    /// Short name of an element, used in the `c/<unit>` label.
+    pub unit: &'static str,
+}
+
+thread_local! {
+    static METADATA: Cell<Option<Metadata>> = const { Cell::new(None) };
+    static ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Declares that one iteration processes `bytes` bytes in `elements` elements named `unit`, so
+/// results are reported as `cpb (c/<unit>)`.
+pub fn set_bytes_and_elements(bytes: u64, elements: u64, unit: &'static str) {
+    METADATA.with(|m| {
+        m.set(Some(Metadata {
+            bytes,
+            elements,
+            unit,
+        }))
+    });
+}
+
+/// Declares that one iteration processes `codepoints` Unicode scalar values, so results are
+/// reported as `cycles/cp`, e.g. for normalization or case mapping where the cost depends on the
+/// number of characters rather than their encoded length.
+///
+/// A count of zero, as for an empty text, clears the counts set before, so that the throughput set
+/// with criterion is reported as usual.
+///
+/// ```rust
+/// # #[cfg(feature = "criterion")]
+/// # {
+/// use criterion::{black_box, measurement::Measurement, Criterion, Throughput};
+/// use criterion_cycles_per_byte::{metadata, CyclesPerByte};
+///
+/// fn bench(c: &mut Criterion<CyclesPerByte>) {
+///     let text = "Grüße aus Köln, Ἀθῆναι";
+///     let codepoints = text.chars().count() as u64;
+///
+///     let mut group = c.benchmark_group("text");
+///     // Still needed for criterion to print the throughput line.
+///     group.throughput(Throughput::Elements(codepoints));
+///     group.bench_function("lowercase", |b| {
+///         metadata::set_codepoints(codepoints);
+///         b.iter(|| black_box(text).to_lowercase())
+///     });
+///     group.finish();
+///     metadata::clear();
+/// }
+///
+/// let measurement = CyclesPerByte::new();
+/// let formatter = measurement.formatter();
+///
+/// metadata::set_codepoints(22);
+/// assert_eq!(
+///     formatter.format_throughput(&Throughput::Elements(22), 1100.0),
+///     "50.0000 cycles/cp"
+/// );
+/// let mut values = [1100.0];
+/// let unit = formatter.scale_throughputs(1100.0, &Throughput::Elements(22), &mut values);
+/// assert_eq!((unit, values), ("cycles/cp", [50.0]));
+///
+/// metadata::set_codepoints(0);
+/// assert!(metadata::get().is_none());
+/// assert_eq!(
+///     formatter.format_throughput(&Throughput::Bytes(100), 1100.0),
+///     "11.0000 cpb"
+/// );
+/// metadata::clear();
+/// # }
+/// ```
+pub fn set_codepoints(codepoints: u64) {
+    if codepoints == 0 {
+        METADATA.with(|m| m.set(None));
+        return;
+    }
+    set_bytes_and_elements(0, codepoints, "cp");
+}
+
+/// Declares that the current benchmark is `id`, typically criterion's `group/function/parameter`
+/// id.
+pub fn set_id<S: Into<String>>(id: S) {
+    ID.with(|i| *i.borrow_mut() = Some(id.into()));
+}
+
+/// Returns the id set on this thread, if any.
+pub fn id() -> Option<String> {
+    ID.with(|i| i.borrow().clone())
+}
+
+/// Returns the byte count encoded in a benchmark `id` such as `"aes/16384"`: the last
+/// `/`-separated part of the id, if it is a positive decimal integer.
+///
+/// Only the last part is considered, since criterion puts the parameter of a `BenchmarkId` there,
+/// so numbers elsewhere in the id, as in `"sha256"` or `"aes/4096/encrypt"`, are not taken for
+/// sizes. The part has to consist of ASCII digits only: suffixes like `KiB`, signs, separators and
+/// surrounding whitespace make it not a number, as does a value of 0 or one that does not fit.
+///
+/// ```rust
+/// use criterion_cycles_per_byte::metadata::infer_bytes_from_id;
+///
+/// assert_eq!(infer_bytes_from_id("aes/16384"), Some(16384));
+/// assert_eq!(infer_bytes_from_id("aead/seal/64"), Some(64));
+/// assert_eq!(infer_bytes_from_id("1024"), Some(1024));
+/// // Only the last part counts, and only if it is all digits.
+/// assert_eq!(infer_bytes_from_id("aes/16/16384"), Some(16384));
+/// assert_eq!(infer_bytes_from_id("aes/4096/encrypt"), None);
+/// assert_eq!(infer_bytes_from_id("sha256"), None);
+/// assert_eq!(infer_bytes_from_id("aes/16KiB"), None);
+/// assert_eq!(infer_bytes_from_id("aes/+16"), None);
+/// assert_eq!(infer_bytes_from_id("aes/"), None);
+/// assert_eq!(infer_bytes_from_id("aes/0"), None);
+/// assert_eq!(infer_bytes_from_id("aes/99999999999999999999"), None);
+/// ```
+pub fn infer_bytes_from_id(id: &str) -> Option<u64> {
+    let last = id.rsplit('/').next()?;
+    if last.is_empty() || !last.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    last.parse().ok().filter(|&bytes| bytes != 0)
+}
+
+/// Calls `f` with the id set on this thread, without cloning it.
+pub(crate) fn with_id<R>(f: impl FnOnce(Option<&str>) -> R) -> R {
+    ID.with(|i| f(i.borrow().as_deref()))
+}
+
+/// Forgets the counts and the id set on this thread.
+pub fn clear() {
+    METADATA.with(|m| m.set(None));
+    ID.with(|i| *i.borrow_mut() = None);
+}
+
+/// Returns the counts set on this thread, if any.
+pub fn get() -> Option<Metadata> {
+    METADATA.with(Cell::get)
+}
@@ -0,0 +1,177 @@
+//! Fixed-function performance counters, read through the Linux `msr` driver.
+//!
+//! The driver exposes each logical CPU's model-specific registers as `/dev/cpu/N/msr`, where a
+//! `pread`/`pwrite` of 8 bytes at offset `addr` reads/writes MSR `addr` on CPU `N`. Every read is
+//! a system call (and an IPI if the calling thread is not currently on CPU `N`), so this backend
+//! is far more expensive than `rdtsc` and only suited to regions of at least several microseconds.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+};
+
+/// Fixed-function counter 0: `INST_RETIRED.ANY`.
+const IA32_FIXED_CTR0: u64 = 0x309;
+/// Fixed-function counter 1: `CPU_CLK_UNHALTED.CORE`.
+const IA32_FIXED_CTR1: u64 = 0x30a;
+/// Per-counter enable bits for the fixed-function counters.
+const IA32_FIXED_CTR_CTRL: u64 = 0x38d;
+/// Global enable bits for the general purpose and fixed-function counters.
+const IA32_PERF_GLOBAL_CTRL: u64 = 0x38f;
+/// General-purpose counter 0.
+const IA32_PMC0: u64 = 0xc1;
+/// Event select register of general-purpose counter 0.
+const IA32_PERFEVTSEL0: u64 = 0x186;
+/// Counts at a fixed rate while the core is not halted.
+const IA32_MPERF: u64 = 0xe7;
+/// Counts at the actual core frequency while the core is not halted.
+const IA32_APERF: u64 = 0xe8;
+
+/// The four `IA32_FIXED_CTR_CTRL` bits of fixed counter 0 (bits 0..=3).
+const FIXED_CTR0_MASK: u64 = 0b1111;
+/// Count in ring 0 only (bit 0 of `IA32_FIXED_CTR_CTRL`).
+const FIXED_CTR0_OS_ONLY: u64 = 0b01;
+/// Count in both ring 0 and ring 3 (bits 4 and 5 of `IA32_FIXED_CTR_CTRL`).
+const FIXED_CTR1_ENABLE: u64 = 0b11 << 4;
+/// Count in ring 3 (bit 16 of `IA32_PERFEVTSELx`).
+const PERFEVTSEL_USR: u64 = 1 << 16;
+/// Enable the counter (bit 22 of `IA32_PERFEVTSELx`).
+const PERFEVTSEL_EN: u64 = 1 << 22;
+/// General-purpose counter 0 enable bit in `IA32_PERF_GLOBAL_CTRL`.
+const GLOBAL_PMC0_ENABLE: u64 = 1;
+/// Fixed counter 0 enable bit in `IA32_PERF_GLOBAL_CTRL`.
+const GLOBAL_FIXED_CTR0_ENABLE: u64 = 1 << 32;
+/// Fixed counter 1 enable bit in `IA32_PERF_GLOBAL_CTRL`.
+const GLOBAL_FIXED_CTR1_ENABLE: u64 = 1 << 33;
+
+pub(crate) struct FixedCounter {
+    file: File,
+}
+
+impl FixedCounter {
+    /// Opens the msr device of the CPU the calling thread runs on and enables fixed counter 1.
+    pub(crate) fn open() -> io::Result<Self> {
+        let file = open_current_cpu(true)?;
+        update_msr(&file, IA32_FIXED_CTR_CTRL, |ctrl| ctrl | FIXED_CTR1_ENABLE)?;
+        update_msr(&file, IA32_PERF_GLOBAL_CTRL, |global| {
+            global | GLOBAL_FIXED_CTR1_ENABLE
+        })?;
+        read_msr(&file, IA32_FIXED_CTR1)?;
+        Ok(FixedCounter { file })
+    }
+
+    #[inline]
+    pub(crate) fn read(&self) -> u64 {
+        read_msr(&self.file, IA32_FIXED_CTR1).expect("failed to read IA32_FIXED_CTR1")
+    }
+}
+
+/// Instructions retired in ring 0 (fixed counter 0). While user space code runs, this only
+/// advances when the kernel runs on its behalf: on interrupts, faults and system calls.
+pub(crate) struct KernelInstructions {
+    file: File,
+}
+
+impl KernelInstructions {
+    /// Opens the msr device of the CPU the calling thread runs on and makes fixed counter 0 count
+    /// ring 0 instructions only.
+    pub(crate) fn open() -> io::Result<Self> {
+        let file = open_current_cpu(true)?;
+        update_msr(&file, IA32_FIXED_CTR_CTRL, |ctrl| {
+            ctrl & !FIXED_CTR0_MASK | FIXED_CTR0_OS_ONLY
+        })?;
+        update_msr(&file, IA32_PERF_GLOBAL_CTRL, |global| {
+            global | GLOBAL_FIXED_CTR0_ENABLE
+        })?;
+        read_msr(&file, IA32_FIXED_CTR0)?;
+        Ok(KernelInstructions { file })
+    }
+
+    #[inline]
+    pub(crate) fn read(&self) -> u64 {
+        read_msr(&self.file, IA32_FIXED_CTR0).expect("failed to read IA32_FIXED_CTR0")
+    }
+}
+
+/// A user-selected event in ring 3, counted by general-purpose counter 0.
+pub(crate) struct ProgrammedCounter {
+    file: File,
+}
+
+impl ProgrammedCounter {
+    /// Opens the msr device of the CPU the calling thread runs on and programs general-purpose
+    /// counter 0 to count `event` with `umask` in user mode, replacing whatever it counted.
+    pub(crate) fn open(event: u8, umask: u8) -> io::Result<Self> {
+        if crate::detect::general_counters() == 0 {
+            return Err(io::Error::other(
+                "the processor has no general-purpose performance counters",
+            ));
+        }
+        let file = open_current_cpu(true)?;
+        let select = u64::from(event) | u64::from(umask) << 8 | PERFEVTSEL_USR | PERFEVTSEL_EN;
+        write_msr(&file, IA32_PERFEVTSEL0, select)?;
+        update_msr(&file, IA32_PERF_GLOBAL_CTRL, |global| {
+            global | GLOBAL_PMC0_ENABLE
+        })?;
+        read_msr(&file, IA32_PMC0)?;
+        Ok(ProgrammedCounter { file })
+    }
+
+    #[inline]
+    pub(crate) fn read(&self) -> u64 {
+        read_msr(&self.file, IA32_PMC0).expect("failed to read IA32_PMC0")
+    }
+}
+
+/// `IA32_APERF` and `IA32_MPERF`, whose ratio over an interval is the average frequency the core
+/// ran at while not halted, relative to its nominal frequency.
+pub(crate) struct PerformanceClocks {
+    file: File,
+}
+
+impl PerformanceClocks {
+    /// Opens the msr device of the CPU the calling thread runs on, read-only.
+    pub(crate) fn open() -> io::Result<Self> {
+        let file = open_current_cpu(false)?;
+        read_msr(&file, IA32_APERF)?;
+        read_msr(&file, IA32_MPERF)?;
+        Ok(PerformanceClocks { file })
+    }
+
+    /// Returns `(APERF, MPERF)`.
+    #[inline]
+    pub(crate) fn read(&self) -> (u64, u64) {
+        let aperf = read_msr(&self.file, IA32_APERF).expect("failed to read IA32_APERF");
+        let mperf = read_msr(&self.file, IA32_MPERF).expect("failed to read IA32_MPERF");
+        (aperf, mperf)
+    }
+}
+
+fn open_current_cpu(write: bool) -> io::Result<File> {
+    let cpu = crate::affinity::current_cpu()?;
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(format!("/dev/cpu/{}/msr", cpu))
+}
+
+/// Replaces the `value` of MSR `addr` with `f(value)`, skipping the write if nothing changes.
+fn update_msr(file: &File, addr: u64, f: impl FnOnce(u64) -> u64) -> io::Result<()> {
+    let value = read_msr(file, addr)?;
+    let updated = f(value);
+    if updated != value {
+        write_msr(file, addr, updated)?;
+    }
+    Ok(())
+}
+
+fn read_msr(file: &File, addr: u64) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    file.read_exact_at(&mut buf, addr)?;
+    Ok(u64::from_ne_bytes(buf))
+}
+
+fn write_msr(file: &File, addr: u64, value: u64) -> io::Result<()> {
+    file.write_all_at(&value.to_ne_bytes(), addr)
+}
@@ -0,0 +1,50 @@
+//! Binding the calling thread's memory to a NUMA node, with Linux's `set_mempolicy`.
+//!
+//! The policy is the kernel's, so neither libnuma nor any privilege is needed, but it only exists
+//! in kernels built with `CONFIG_NUMA`. It applies to pages as they are first touched, not to
+//! those already backed by memory.
+
+use std::io;
+
+/// Allocate only from the nodes in the mask.
+const MPOL_BIND: libc::c_int = 2;
+
+/// Restricts the memory the calling thread allocates from now on to NUMA node `node`, so large
+/// buffers live next to the core measuring them and cpb does not depend on where they happened to
+/// land.
+///
+/// Call it on the benchmark thread before allocating the data, and pin that thread to a CPU of the
+/// same node with [`CyclesPerByte::pinned_to`]; [`CyclesPerByte::pinned`] keeps whichever CPU the
+/// thread is on. Allocations fail rather than spill once `node` is full.
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] if `node` does not exist or is not allowed, or if the
+/// kernel lacks NUMA support. Requires the `numa` feature, on Linux.
+///
+/// [`CyclesPerByte::pinned_to`]: crate::CyclesPerByte::pinned_to
+/// [`CyclesPerByte::pinned`]: crate::CyclesPerByte::pinned
+///
+/// ```rust
+/// use criterion_cycles_per_byte::bind_to_numa_node;
+///
+/// if let Err(e) = bind_to_numa_node(0) {
+///     eprintln!("cannot bind to NUMA node 0 ({}), data may be remote", e);
+/// }
+/// let data = vec![0u8; 64 << 20];
+/// # drop(data);
+///
+/// assert!(bind_to_numa_node(1 << 20).is_err());
+/// ```
+pub fn bind_to_numa_node(node: u32) -> io::Result<()> {
+    let bits = libc::c_ulong::BITS;
+    let mut mask = vec![0 as libc::c_ulong; (node / bits) as usize + 1];
+    mask[(node / bits) as usize] = 1 << (node % bits);
+    // The kernel reads one bit less than `maxnode`, a long-standing quirk that libnuma works
+    // around the same way.
+    let maxnode = mask.len() as libc::c_ulong * libc::c_ulong::from(bits) + 1;
+    let result =
+        unsafe { libc::syscall(libc::SYS_set_mempolicy, MPOL_BIND, mask.as_ptr(), maxnode) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! Package energy from RAPL (Running Average Power Limit), read through Linux's powercap driver.
+//!
+//! Each RAPL domain is a zone under `/sys/class/powercap`, e.g. `intel-rapl:0` for the first
+//! package (AMD processors use the same name) and `intel-rapl:0:0` for its cores. A zone's
+//! `energy_uj` holds the energy it has consumed in microjoules, and `max_energy_range_uj` the
+//! value after which `energy_uj` wraps around to 0.
+
+#[cfg(feature = "criterion")]
+use criterion::{
+    measurement::{Measurement, ValueFormatter},
+    Throughput,
+};
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+/// The zone of the first package.
+const PACKAGE_ZONE: &str = "/sys/class/powercap/intel-rapl:0";
+
+/// `EnergyPerByte` measures the energy a RAPL domain consumes, in microjoules, and reports
+/// `µJ/byte`: the power efficiency to go with the cycle count of [`CyclesPerByte`].
+///
+/// The driver updates the counter about once a millisecond, and the hardware counts in units of
+/// typically 15.3 or 61 µJ, so a single short batch reads as 0 or one unit. criterion's samples
+/// need to last many milliseconds for the result to mean anything; raise `measurement_time`
+/// until criterion's estimates settle. The package domain also counts everything else the
+/// package does meanwhile, so keep the machine otherwise idle.
+///
+/// # Permissions
+///
+/// Since Linux 5.10 the energy counters are only readable by root. Run the benchmark as root or
+/// make the zone's counter readable, e.g.
+/// `chmod o+r /sys/class/powercap/intel-rapl:0/energy_uj`; [`EnergyPerByte::new`] fails with
+/// [`io::ErrorKind::PermissionDenied`] otherwise. Each read is a system call.
+///
+/// # Wraparound
+///
+/// `energy_uj` wraps to 0 after `max_energy_range_uj`. A region whose end reading is below its
+/// start is taken to have wrapped once, which covers any region shorter than the better part of
+/// an hour at full load.
+///
+/// [`CyclesPerByte`]: crate::CyclesPerByte
+///
+/// ```rust,no_run
+/// use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+/// use criterion_cycles_per_byte::EnergyPerByte;
+/// use std::time::Duration;
+///
+/// fn bench(c: &mut Criterion<EnergyPerByte>) {
+///     let data = vec![0u8; 1 << 20];
+///     let mut group = c.benchmark_group("xor");
+///     group.throughput(Throughput::Bytes(data.len() as u64));
+///     group.bench_function("1MiB", |b| b.iter(|| data.iter().fold(0, |acc, x| acc ^ x)));
+///     group.finish();
+/// }
+///
+/// fn energy() -> Criterion<EnergyPerByte> {
+///     let measurement = EnergyPerByte::new().expect("cannot read the RAPL energy counter");
+///     Criterion::default()
+///         .with_measurement(measurement)
+///         .measurement_time(Duration::from_secs(20))
+/// }
+///
+/// criterion_group!(
+///     name = my_bench;
+///     config = energy();
+///     targets = bench
+/// );
+/// criterion_main!(my_bench);
+/// ```
+#[derive(Clone)]
+pub struct EnergyPerByte {
+    energy: Arc<File>,
+    /// `max_energy_range_uj` of the zone.
+    range: u64,
+    #[cfg(feature = "criterion")]
+    formatter: EnergyPerByteFormatter,
+}
+
+impl EnergyPerByte {
+    /// Measures the energy of the first package, `intel-rapl:0`.
+    ///
+    /// Fails if the zone does not exist, e.g. without RAPL or in most virtual machines, or if
+    /// `energy_uj` is not readable, see [Permissions](EnergyPerByte#permissions).
+    pub fn new() -> io::Result<Self> {
+        EnergyPerByte::with_zone(PACKAGE_ZONE)
+    }
+
+    /// Measures the energy of the powercap zone at `zone`, e.g.
+    /// `/sys/class/powercap/intel-rapl:0:0` for the cores of the first package.
+    ///
+    /// Fails if the zone's `energy_uj` or `max_energy_range_uj` cannot be read or parsed. The
+    /// zone is only ever read, so any directory holding those two files works, which the example
+    /// uses to show a wrapping counter:
+    ///
+    /// ```rust
+    /// use criterion::{measurement::Measurement, Throughput};
+    /// use criterion_cycles_per_byte::EnergyPerByte;
+    /// use std::fs;
+    ///
+    /// let zone = std::env::temp_dir().join(format!("cpb-rapl-{}", std::process::id()));
+    /// fs::create_dir_all(&zone)?;
+    /// fs::write(zone.join("max_energy_range_uj"), "1000000\n")?;
+    /// fs::write(zone.join("energy_uj"), "999900\n")?;
+    /// let measurement = EnergyPerByte::with_zone(&zone)?;
+    ///
+    /// let start = measurement.start();
+    /// fs::write(zone.join("energy_uj"), "250\n")?;
+    /// assert_eq!(measurement.end(start), 350);
+    ///
+    /// let formatter = measurement.formatter();
+    /// assert_eq!(formatter.format_value(61.0), "61.0000 µJ");
+    /// assert_eq!(formatter.format_throughput(&Throughput::Bytes(4), 61.0), "15.2500 µJ/byte");
+    /// assert_eq!(formatter.format_throughput(&Throughput::Elements(2), 61.0), "30.5000 µJ/e");
+    ///
+    /// fs::write(zone.join("energy_uj"), "not a number\n")?;
+    /// assert!(EnergyPerByte::with_zone(&zone).is_err());
+    /// assert!(EnergyPerByte::with_zone(zone.join("missing")).is_err());
+    /// fs::remove_dir_all(&zone)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn with_zone<P: AsRef<Path>>(zone: P) -> io::Result<Self> {
+        let zone = zone.as_ref();
+        let range = parse(&fs::read_to_string(zone.join("max_energy_range_uj"))?)?;
+        let energy = File::open(zone.join("energy_uj"))?;
+        read_energy(&energy)?;
+        Ok(EnergyPerByte {
+            energy: Arc::new(energy),
+            range,
+            #[cfg(feature = "criterion")]
+            formatter: EnergyPerByteFormatter,
+        })
+    }
+
+    /// Reads the zone's energy counter, in microjoules.
+    pub fn read(&self) -> u64 {
+        read_energy(&self.energy).expect("failed to read the RAPL energy counter")
+    }
+
+    /// Returns the energy consumed between the readings `start` and `end` of
+    /// [`EnergyPerByte::read`], assuming at most one wraparound, see
+    /// [Wraparound](EnergyPerByte#wraparound).
+    pub fn elapsed(&self, start: u64, end: u64) -> u64 {
+        if end >= start {
+            end - start
+        } else {
+            end + self.range.saturating_sub(start)
+        }
+    }
+}
+
+/// Reads a counter value from the start of `file`, which sysfs regenerates on every read.
+fn read_energy(file: &File) -> io::Result<u64> {
+    let mut buf = [0u8; 24];
+    let len = file.read_at(&mut buf, 0)?;
+    let text = std::str::from_utf8(&buf[..len])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    parse(text)
+}
+
+fn parse(text: &str) -> io::Result<u64> {
+    text.trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(feature = "criterion")]
+impl Measurement for EnergyPerByte {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> Self::Intermediate {
+        self.read()
+    }
+
+    fn end(&self, i: Self::Intermediate) -> Self::Value {
+        self.elapsed(i, self.read())
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1.saturating_add(*v2)
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &self.formatter
+    }
+}
+
+/// Formats microjoules, and microjoules per byte or element.
+#[cfg(feature = "criterion")]
+#[derive(Clone)]
+struct EnergyPerByteFormatter;
+
+#[cfg(feature = "criterion")]
+impl ValueFormatter for EnergyPerByteFormatter {
+    fn format_value(&self, value: f64) -> String {
+        format!("{:.4} µJ", value)
+    }
+
+    fn format_throughput(&self, throughput: &Throughput, value: f64) -> String {
+        match *throughput {
+            Throughput::Bytes(b) | Throughput::BytesDecimal(b) => {
+                format!("{:.4} µJ/byte", value / b as f64)
+            }
+            Throughput::Elements(e) => format!("{:.4} µJ/e", value / e as f64),
+        }
+    }
+
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "µJ"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        match *throughput {
+            Throughput::Bytes(n) | Throughput::BytesDecimal(n) => {
+                for val in values {
+                    *val /= n as f64;
+                }
+                "µJ/byte"
+            }
+            Throughput::Elements(n) => {
+                for val in values {
+                    *val /= n as f64;
+                }
+                "µJ/e"
+            }
+        }
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "µJ"
+    }
+}
@@ -0,0 +1,57 @@
+//! Unhalted core cycles read with `rdpmc` from user space.
+//!
+//! `rdpmc` reads a performance counter without entering the kernel, at a cost close to `rdtsc`,
+//! but raises a general protection fault in user mode unless the kernel has set `CR4.PCE`. Linux
+//! reports whether it has in `/sys/bus/event_source/devices/*/rdpmc`, which is checked before the
+//! instruction is ever executed.
+
+use std::fs;
+
+/// `rdpmc` selector of fixed-function counter 1, `CPU_CLK_UNHALTED.CORE`: bit 30 selects the
+/// fixed-function counters.
+const FIXED_CTR1: u32 = 1 << 30 | 1;
+
+/// The perf PMU devices whose `rdpmc` setting applies to the cores: `cpu`, or `cpu_core` and
+/// `cpu_atom` on hybrid processors.
+const PMU_DEVICES: [&str; 3] = ["cpu", "cpu_core", "cpu_atom"];
+
+/// Returns whether fixed counter 1 can be read with `rdpmc` and is counting in user mode.
+pub(crate) fn available() -> bool {
+    // 2 sets CR4.PCE for every task; the default of 1 only for tasks with an mmapped perf event.
+    let always_enabled = PMU_DEVICES.iter().any(|device| {
+        fs::read_to_string(format!("/sys/bus/event_source/devices/{}/rdpmc", device))
+            .is_ok_and(|setting| setting.trim() == "2")
+    });
+    if !always_enabled || crate::detect::fixed_counters() < 2 {
+        return false;
+    }
+    let start = read();
+    let mut spin = 0u64;
+    while spin < 1000 {
+        spin = std::hint::black_box(spin + 1);
+    }
+    read() > start
+}
+
+#[inline]
+pub(crate) fn read() -> u64 {
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    {
+        let (low, high): (u32, u32);
+        // SAFETY: only reached after `available` found user-mode `rdpmc` enabled.
+        unsafe {
+            core::arch::asm!(
+                "rdpmc",
+                in("ecx") FIXED_CTR1,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        u64::from(high) << 32 | u64::from(low)
+    }
+
+    // Only compiled for documentation builds, everything else hits the `compile_error!` in lib.rs.
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+    0
+}
@@ -0,0 +1,480 @@
+//! Distribution statistics over measured deltas, in constant memory while measuring.
+
+use crate::{read_binary, read_binary_header, DumpHeader};
+use std::{io, path::Path};
+
+/// Summary statistics of the deltas measured since streaming statistics were enabled.
+///
+/// The quantiles of streaming statistics are estimates from the P² algorithm (Jain and Chlamtac,
+/// 1985), which tracks each one with five markers instead of storing the samples. They are exact
+/// for up to five samples and typically within a fraction of a percent of the true quantile for
+/// smooth distributions; for distributions with a few widely separated clusters they can land
+/// between two clusters. Those of [`CycleStats::merge`] are exact. All fields are zero if nothing
+/// was measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CycleStats {
+    /// The number of deltas.
+    pub count: u64,
+    /// The smallest delta.
+    pub min: u64,
+    /// The largest delta.
+    pub max: u64,
+    /// The arithmetic mean of the deltas.
+    pub mean: f64,
+    /// The sample standard deviation of the deltas, zero for fewer than two.
+    pub std_dev: f64,
+    /// The estimated median.
+    pub p50: f64,
+    /// The estimated 90th percentile.
+    pub p90: f64,
+    /// The estimated 99th percentile.
+    pub p99: f64,
+}
+
+impl CycleStats {
+    /// Computes the statistics of all samples of `sets` together, e.g. the recorded samples of
+    /// several runs or machines.
+    ///
+    /// The samples are pooled: each counts once, whichever set it is from, so a set with more
+    /// samples weighs more. Nothing is normalized, so the sets should come from the same
+    /// benchmark on comparable counters; cycle counts of machines whose TSCs tick at different
+    /// rates mean different times. The statistics are exact, the quantiles interpolated linearly
+    /// between the two closest ranks, which makes `p50` the median.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CycleStats;
+    ///
+    /// let stats = CycleStats::merge(&[vec![10, 30], vec![20, 40, 50]]);
+    /// assert_eq!((stats.count, stats.min, stats.max), (5, 10, 50));
+    /// assert_eq!((stats.mean, stats.p50, stats.p90), (30.0, 30.0, 46.0));
+    /// assert!((stats.std_dev - 15.811).abs() < 0.001);
+    /// assert_eq!(CycleStats::merge(&[vec![], vec![]]), CycleStats::default());
+    /// ```
+    pub fn merge(sets: &[Vec<u64>]) -> CycleStats {
+        let mut sorted: Vec<u64> = sets.iter().flatten().copied().collect();
+        sorted.sort_unstable();
+        let count = sorted.len();
+        if count == 0 {
+            return CycleStats::default();
+        }
+        let mean = sorted.iter().map(|&x| u128::from(x)).sum::<u128>() as f64 / count as f64;
+        let squares: f64 = sorted.iter().map(|&x| (x as f64 - mean).powi(2)).sum();
+        let quantile = |p: f64| {
+            let rank = p * (count - 1) as f64;
+            let low = rank.floor() as usize;
+            let high = rank.ceil() as usize;
+            sorted[low] as f64 + (sorted[high] as f64 - sorted[low] as f64) * (rank - low as f64)
+        };
+        CycleStats {
+            count: count as u64,
+            min: sorted[0],
+            max: sorted[count - 1],
+            mean,
+            std_dev: if count > 1 {
+                (squares / (count - 1) as f64).sqrt()
+            } else {
+                0.0
+            },
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+        }
+    }
+
+    /// Reads the sample dumps at `paths`, written by
+    /// [`CyclesPerByte::dump_binary`](crate::CyclesPerByte::dump_binary) on any number of
+    /// machines, and merges their samples as [`CycleStats::merge`] does.
+    ///
+    /// The headers are returned along with the statistics, in the order of `paths`, so the
+    /// contribution of each machine can be told apart: `machine` is the identifier set with
+    /// [`CyclesPerByte::with_machine_id`](crate::CyclesPerByte::with_machine_id) and `count` the
+    /// number of its samples. Dumps whose architecture or frequency differ are merged all the
+    /// same; check the headers if that matters. Fails if any file cannot be read, see
+    /// [`read_binary`](crate::read_binary).
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::{CycleStats, CyclesPerByte};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// // Two machines, one measuring 1 to 100 cycles and the other 101 to 200.
+    /// let mut paths = Vec::new();
+    /// for (machine, deltas) in [("a", 1..=100), ("b", 101..=200)] {
+    ///     let readings: Vec<u64> = deltas.clone().flat_map(|d| [0, d]).collect();
+    ///     let readings = Arc::new(Mutex::new(readings));
+    ///     let measurement = CyclesPerByte::new()
+    ///         .with_counter(move || readings.lock().unwrap().remove(0))
+    ///         .with_machine_id(machine)
+    ///         .record_samples();
+    ///     for _ in deltas {
+    ///         measurement.measure(|| ());
+    ///     }
+    ///     let path = std::env::temp_dir().join(format!("cpb-merge-{}-{}.bin", machine, std::process::id()));
+    ///     measurement.dump_binary(&path)?;
+    ///     paths.push(path);
+    /// }
+    ///
+    /// let (stats, headers) = CycleStats::merge_dumps(&paths)?;
+    /// assert_eq!((stats.count, stats.min, stats.max), (200, 1, 200));
+    /// assert_eq!((stats.mean, stats.p50), (100.5, 100.5));
+    /// assert!((stats.p90 - 180.1).abs() < 1e-9 && (stats.p99 - 198.01).abs() < 1e-9);
+    /// assert!((stats.std_dev - 3350f64.sqrt()).abs() < 1e-9);
+    /// let machines: Vec<_> = headers.iter().map(|h| (h.machine.as_deref(), h.count)).collect();
+    /// assert_eq!(machines, vec![(Some("a"), 100), (Some("b"), 100)]);
+    /// for path in paths {
+    ///     std::fs::remove_file(path)?;
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn merge_dumps<P: AsRef<Path>>(paths: &[P]) -> io::Result<(CycleStats, Vec<DumpHeader>)> {
+        let mut sets = Vec::with_capacity(paths.len());
+        let mut headers = Vec::with_capacity(paths.len());
+        for path in paths {
+            headers.push(read_binary_header(path)?);
+            sets.push(read_binary(path)?);
+        }
+        Ok((CycleStats::merge(&sets), headers))
+    }
+}
+
+/// Running count, extremes, sum and quantile estimators.
+pub(crate) struct Streaming {
+    count: u64,
+    min: u64,
+    max: u64,
+    sum: u128,
+    /// Welford's running mean and sum of squared differences from it, for the variance.
+    running_mean: f64,
+    squares: f64,
+    p50: P2,
+    p90: P2,
+    p99: P2,
+}
+
+impl Streaming {
+    pub(crate) fn new() -> Self {
+        Streaming {
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            sum: 0,
+            running_mean: 0.0,
+            squares: 0.0,
+            p50: P2::new(0.5),
+            p90: P2::new(0.9),
+            p99: P2::new(0.99),
+        }
+    }
+
+    pub(crate) fn push(&mut self, delta: u64) {
+        self.count += 1;
+        self.min = self.min.min(delta);
+        self.max = self.max.max(delta);
+        self.sum += u128::from(delta);
+        let x = delta as f64;
+        let difference = x - self.running_mean;
+        self.running_mean += difference / self.count as f64;
+        self.squares += difference * (x - self.running_mean);
+        self.p50.push(x);
+        self.p90.push(x);
+        self.p99.push(x);
+    }
+
+    /// The standard deviation relative to the mean, if there are at least two deltas.
+    #[cfg_attr(not(feature = "criterion"), allow(dead_code))]
+    pub(crate) fn coefficient_of_variation(&self) -> Option<f64> {
+        let stats = self.stats();
+        if stats.count < 2 || stats.mean == 0.0 {
+            return None;
+        }
+        Some(stats.std_dev / stats.mean)
+    }
+
+    pub(crate) fn stats(&self) -> CycleStats {
+        if self.count == 0 {
+            return CycleStats::default();
+        }
+        CycleStats {
+            count: self.count,
+            min: self.min,
+            max: self.max,
+            mean: self.sum as f64 / self.count as f64,
+            std_dev: if self.count > 1 {
+                (self.squares / (self.count - 1) as f64).sqrt()
+            } else {
+                0.0
+            },
+            p50: self.p50.estimate(),
+            p90: self.p90.estimate(),
+            p99: self.p99.estimate(),
+        }
+    }
+}
+
+/// Returns the exact median of `samples`, the mean of the two middle values for an even count.
+pub(crate) fn median(samples: &[u64]) -> Option<f64> {
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[mid - 1] as f64 + sorted[mid] as f64) / 2.0),
+        _ => Some(sorted[mid] as f64),
+    }
+}
+
+/// The most buckets [`mode`] counts into.
+const MODE_BUCKETS: u64 = 4096;
+
+/// Returns the most frequent value of `samples`, the smallest one on ties.
+///
+/// The samples are counted into at most [`MODE_BUCKETS`] equally wide buckets spanning their
+/// range, then the most frequent exact value is taken from the fullest bucket.
+pub(crate) fn mode(samples: &[u64]) -> Option<u64> {
+    let min = *samples.iter().min()?;
+    let max = *samples.iter().max()?;
+    let width = ((max - min) / MODE_BUCKETS).saturating_add(1);
+    let mut buckets = vec![0u64; ((max - min) / width + 1) as usize];
+    for &sample in samples {
+        buckets[((sample - min) / width) as usize] += 1;
+    }
+    let fullest = (0..buckets.len()).max_by_key(|&i| (buckets[i], std::cmp::Reverse(i)))?;
+
+    let low = min + fullest as u64 * width;
+    let mut values: Vec<u64> = samples
+        .iter()
+        .copied()
+        .filter(|&sample| sample >= low && sample - low < width)
+        .collect();
+    values.sort_unstable();
+    let mut best = (0, values[0]);
+    for run in values.chunk_by(|a, b| a == b) {
+        if run.len() > best.0 {
+            best = (run.len(), run[0]);
+        }
+    }
+    Some(best.1)
+}
+
+/// A running estimate of the median, in constant memory.
+pub(crate) struct RunningMedian(P2);
+
+impl RunningMedian {
+    pub(crate) fn new() -> Self {
+        RunningMedian(P2::new(0.5))
+    }
+
+    pub(crate) fn push(&mut self, delta: u64) {
+        self.0.push(delta as f64);
+    }
+
+    /// The estimate, or `None` before the first delta.
+    pub(crate) fn get(&self) -> Option<f64> {
+        (self.0.count != 0).then(|| self.0.estimate())
+    }
+}
+
+/// A P² estimator of the `p`-quantile.
+///
+/// Five markers track the minimum, the `p/2`-, `p`- and `(1+p)/2`-quantiles and the maximum. Each
+/// observation shifts the actual positions of the markers above it, and each of the three middle
+/// markers is moved by one position whenever it lags its desired position by at least one, with
+/// its height adjusted by a piecewise-parabolic fit through its neighbours, or linearly if the
+/// parabola would leave their range.
+struct P2 {
+    p: f64,
+    /// The number of observations so far, which until five are buffered in `heights`.
+    count: usize,
+    heights: [f64; 5],
+    /// Actual marker positions, 1-based.
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+}
+
+impl P2 {
+    fn new(p: f64) -> Self {
+        P2 {
+            p,
+            count: 0,
+            heights: [0.0; 5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    fn push(&mut self, x: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = x;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        // The cell the observation falls into, extending the extremes if needed.
+        let h = &mut self.heights;
+        let k = if x < h[0] {
+            h[0] = x;
+            0
+        } else if x >= h[4] {
+            h[4] = x;
+            3
+        } else {
+            (0..4).find(|&i| x < h[i + 1]).unwrap()
+        };
+        for position in &mut self.positions[k + 1..] {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(&self.increments) {
+            *desired += increment;
+        }
+
+        for i in 1..4 {
+            let n = &self.positions;
+            let d = self.desired[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let q = &self.heights;
+                let parabolic = q[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                self.heights[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                self.positions[i] += d;
+            }
+        }
+    }
+
+    fn estimate(&self) -> f64 {
+        if self.count >= 5 {
+            return self.heights[2];
+        }
+        // Too few observations for the markers: interpolate the sorted buffer.
+        let mut sorted = self.heights;
+        let sorted = &mut sorted[..self.count];
+        sorted.sort_by(f64::total_cmp);
+        let rank = self.p * (self.count - 1) as f64;
+        let below = rank.floor() as usize;
+        let above = rank.ceil() as usize;
+        sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+    }
+}
+
+/// Returns the two-sided p-value of Welch's t-test of the hypothesis that `a` and `b` come from
+/// distributions with the same mean, or `None` if either has fewer than two samples.
+///
+/// With `s²` the sample variances and `n` the sample sizes, the statistic is
+/// `t = (mean_a - mean_b) / sqrt(s²_a/n_a + s²_b/n_b)` with the Welch-Satterthwaite degrees of
+/// freedom `ν`, and the p-value is `P(|T| ≥ |t|)` for Student's t distribution with `ν` degrees of
+/// freedom, which is the regularized incomplete beta function `I_{ν/(ν+t²)}(ν/2, 1/2)`.
+pub(crate) fn welch_p_value(a: &[u64], b: &[u64]) -> Option<f64> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+    let (a, b) = (summarize(a), summarize(b));
+    let var_a = a.std_dev * a.std_dev / a.count as f64;
+    let var_b = b.std_dev * b.std_dev / b.count as f64;
+    let var = var_a + var_b;
+    if var == 0.0 {
+        // Both samples are constant, so their means either differ or they do not.
+        return Some(if a.mean == b.mean { 1.0 } else { 0.0 });
+    }
+    let t = (a.mean - b.mean) / var.sqrt();
+    let df =
+        var * var / (var_a * var_a / (a.count - 1) as f64 + var_b * var_b / (b.count - 1) as f64);
+    Some(regularized_beta(df / (df + t * t), df / 2.0, 0.5))
+}
+
+fn summarize(samples: &[u64]) -> CycleStats {
+    let mut streaming = Streaming::new();
+    for &sample in samples {
+        streaming.push(sample);
+    }
+    streaming.stats()
+}
+
+/// The regularized incomplete beta function `I_x(a, b)`, from its continued fraction, which
+/// converges quickly for `x < (a + 1) / (a + b + 2)`; above that `I_x(a, b) = 1 - I_{1-x}(b, a)`
+/// is evaluated instead.
+fn regularized_beta(x: f64, a: f64, b: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_continued_fraction(x, a, b) / a
+    } else {
+        1.0 - front * beta_continued_fraction(1.0 - x, b, a) / b
+    }
+}
+
+/// Evaluates the continued fraction of the incomplete beta function with the modified Lentz
+/// method.
+fn beta_continued_fraction(x: f64, a: f64, b: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    const EPSILON: f64 = 1e-15;
+    const MAX_TERMS: u32 = 1000;
+
+    let nonzero = |v: f64| if v.abs() < TINY { TINY } else { v };
+    let mut c = 1.0;
+    let mut d = 1.0 / nonzero(1.0 - (a + b) * x / (a + 1.0));
+    let mut fraction = d;
+    for m in 1..=MAX_TERMS {
+        let m = f64::from(m);
+        // The even and odd terms of the fraction.
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / nonzero(1.0 + even * d);
+        c = nonzero(1.0 + even / c);
+        fraction *= d * c;
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / nonzero(1.0 + odd * d);
+        c = nonzero(1.0 + odd / c);
+        let step = d * c;
+        fraction *= step;
+        if (step - 1.0).abs() < EPSILON {
+            break;
+        }
+    }
+    fraction
+}
+
+/// `ln Γ(x)` for `x > 0`, from the Lanczos approximation with `g = 7`, accurate to about 15
+/// significant digits.
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // Reflection: Γ(x) Γ(1 - x) = π / sin(πx).
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, &coefficient) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += coefficient / (x + i as f64);
+    }
+    let t = x + G + 0.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
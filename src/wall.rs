@@ -0,0 +1,217 @@
+//! Cycles and wall-clock time measured over the same regions.
+
+use crate::CyclesPerByte;
+use criterion::{
+    measurement::{Measurement, ValueFormatter},
+    Throughput,
+};
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// The cycles and nanoseconds of one region, or of several added up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CyclesAndNanos {
+    /// Counter ticks of the wrapped [`CyclesPerByte`].
+    pub cycles: u64,
+    /// Nanoseconds of [`Instant`].
+    pub nanos: u64,
+}
+
+impl CyclesAndNanos {
+    /// Returns the cycles per nanosecond, i.e. the implied frequency in GHz, or `None` if no time
+    /// passed.
+    ///
+    /// ```rust
+    /// use criterion_cycles_per_byte::CyclesAndNanos;
+    ///
+    /// let region = CyclesAndNanos { cycles: 3_000, nanos: 1_250 };
+    /// assert_eq!(region.ghz(), Some(2.4));
+    /// assert_eq!(CyclesAndNanos::default().ghz(), None);
+    /// ```
+    pub fn ghz(&self) -> Option<f64> {
+        if self.nanos == 0 {
+            return None;
+        }
+        Some(self.cycles as f64 / self.nanos as f64)
+    }
+}
+
+/// Totals of the benchmark being measured, for the formatter.
+#[derive(Default)]
+struct Totals {
+    cycles: AtomicU64,
+    nanos: AtomicU64,
+    /// Set once the totals have been formatted, so that the next region starts new ones.
+    reported: AtomicBool,
+}
+
+impl Totals {
+    fn add(&self, region: CyclesAndNanos) {
+        if self.reported.swap(false, Ordering::Relaxed) {
+            self.cycles.store(0, Ordering::Relaxed);
+            self.nanos.store(0, Ordering::Relaxed);
+        }
+        self.cycles.fetch_add(region.cycles, Ordering::Relaxed);
+        self.nanos.fetch_add(region.nanos, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> CyclesAndNanos {
+        CyclesAndNanos {
+            cycles: self.cycles.load(Ordering::Relaxed),
+            nanos: self.nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `CyclesAndWallTime` measures each region with a [`CyclesPerByte`] and [`Instant`] at once,
+/// and reports the cycles together with the frequency they imply, `cycles/ns` in GHz.
+///
+/// criterion sees the cycle count, and the nanoseconds travel alongside it in [`CyclesAndNanos`].
+/// The frequency printed is that of all regions of the benchmark being reported, warm-up included.
+/// With unhalted core cycles it is the clock the core ran at, which reveals throttling; with
+/// `rdtsc` it only exposes a misbehaving counter.
+///
+/// ```rust
+/// use criterion::{measurement::Measurement, Throughput};
+/// use criterion_cycles_per_byte::{CyclesAndWallTime, CyclesPerByte};
+///
+/// let measurement = CyclesAndWallTime::new(CyclesPerByte::new());
+/// let start = measurement.start();
+/// std::thread::sleep(std::time::Duration::from_millis(1));
+/// let region = measurement.end(start);
+/// assert!(region.nanos >= 1_000_000);
+/// assert_eq!(measurement.totals(), region);
+/// assert_eq!(measurement.to_f64(&region), region.cycles as f64);
+///
+/// let formatter = measurement.formatter();
+/// let value = formatter.format_value(2048.0);
+/// assert!(value.starts_with("2048.0000 cycles @ ") && value.ends_with(" GHz"), "{}", value);
+/// let throughput = formatter.format_throughput(&Throughput::Bytes(1024), 2048.0);
+/// assert!(throughput.starts_with("2.0000 cpb @ "), "{}", throughput);
+/// ```
+#[derive(Clone)]
+pub struct CyclesAndWallTime {
+    formatter: CyclesAndWallTimeFormatter,
+}
+
+impl CyclesAndWallTime {
+    /// Measures cycles with `cycles`, which keeps its backend and options, and wall-clock time
+    /// with [`Instant`].
+    pub fn new(cycles: CyclesPerByte) -> Self {
+        CyclesAndWallTime {
+            formatter: CyclesAndWallTimeFormatter {
+                cycles,
+                totals: Arc::new(Totals::default()),
+            },
+        }
+    }
+
+    /// Returns the cycles and nanoseconds of the current benchmark's regions so far, see the
+    /// [type documentation](CyclesAndWallTime) for when they start.
+    pub fn totals(&self) -> CyclesAndNanos {
+        self.formatter.totals.get()
+    }
+}
+
+/// The values are added field by field, saturating:
+///
+/// ```rust
+/// use criterion::measurement::Measurement;
+/// use criterion_cycles_per_byte::{CyclesAndNanos, CyclesAndWallTime, CyclesPerByte};
+///
+/// let measurement = CyclesAndWallTime::new(CyclesPerByte::new());
+/// let a = CyclesAndNanos { cycles: 300, nanos: 100 };
+/// let b = CyclesAndNanos { cycles: u64::MAX, nanos: 20 };
+/// assert_eq!(measurement.add(&a, &a), CyclesAndNanos { cycles: 600, nanos: 200 });
+/// assert_eq!(measurement.add(&a, &b), CyclesAndNanos { cycles: u64::MAX, nanos: 120 });
+/// assert_eq!(measurement.add(&a, &measurement.zero()), a);
+/// assert_eq!(measurement.to_f64(&a), 300.0);
+/// ```
+impl Measurement for CyclesAndWallTime {
+    type Intermediate = (Instant, u64);
+    type Value = CyclesAndNanos;
+
+    fn start(&self) -> Self::Intermediate {
+        let time = Instant::now();
+        (time, self.formatter.cycles.start())
+    }
+
+    fn end(&self, (time, cycles): Self::Intermediate) -> Self::Value {
+        let cycles = self.formatter.cycles.end(cycles);
+        let nanos = u64::try_from(time.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let region = CyclesAndNanos { cycles, nanos };
+        self.formatter.totals.add(region);
+        region
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        CyclesAndNanos {
+            cycles: v1.cycles.saturating_add(v2.cycles),
+            nanos: v1.nanos.saturating_add(v2.nanos),
+        }
+    }
+
+    fn zero(&self) -> Self::Value {
+        CyclesAndNanos::default()
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        value.cycles as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &self.formatter
+    }
+}
+
+#[derive(Clone)]
+struct CyclesAndWallTimeFormatter {
+    cycles: CyclesPerByte,
+    totals: Arc<Totals>,
+}
+
+impl CyclesAndWallTimeFormatter {
+    /// Appends the implied frequency of the current benchmark to `formatted`.
+    fn with_frequency(&self, formatted: String) -> String {
+        self.totals.reported.store(true, Ordering::Relaxed);
+        match self.totals.get().ghz() {
+            Some(ghz) => format!("{} @ {:.3} GHz", formatted, ghz),
+            None => formatted,
+        }
+    }
+}
+
+impl ValueFormatter for CyclesAndWallTimeFormatter {
+    fn format_value(&self, value: f64) -> String {
+        self.with_frequency(self.cycles.formatter().format_value(value))
+    }
+
+    fn format_throughput(&self, throughput: &Throughput, value: f64) -> String {
+        self.with_frequency(self.cycles.formatter().format_throughput(throughput, value))
+    }
+
+    fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+        self.cycles.formatter().scale_values(typical_value, values)
+    }
+
+    fn scale_throughputs(
+        &self,
+        typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        self.cycles
+            .formatter()
+            .scale_throughputs(typical_value, throughput, values)
+    }
+
+    fn scale_for_machines(&self, values: &mut [f64]) -> &'static str {
+        self.cycles.formatter().scale_for_machines(values)
+    }
+}